@@ -1,16 +1,229 @@
-use std::fmt::{Display, Formatter, Result};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result},
+};
 
-#[derive(Debug)]
-pub enum BasicError {
-    SyntaxError(String),
-    RuntimeError(String),
+/// The kinds of error, which decide how an error is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A line couldn't be parsed, or the blocks in a program don't match.
+    Syntax,
+    /// Something went wrong while running a statement.
+    Runtime,
+    /// The program was stopped, e.g. by `stop` or Ctrl-C, and can be
+    /// continued with `cont`.
+    Break,
+}
+
+/// The code of each error, as returned by `err` in an error handler. Codes
+/// follow Microsoft BASIC's where there's a matching error, so they must
+/// not change once programs rely on them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Not an error: the program was stopped.
+    Break = 0,
+    NextWithoutFor = 1,
+    Syntax = 2,
+    ReturnWithoutGosub = 3,
+    OutOfData = 4,
+    /// A function or statement was given a value it can't use. Errors with
+    /// no more specific code have this one.
+    IllegalFunctionCall = 5,
+    Overflow = 6,
+    OutOfMemory = 7,
+    UndefinedLine = 8,
+    SubscriptOutOfRange = 9,
+    DuplicateDefinition = 10,
+    DivisionByZero = 11,
+    IllegalDirect = 12,
+    TypeMismatch = 13,
+    CantContinue = 17,
+    UndefinedFunction = 18,
+    ResumeWithoutError = 20,
+    WhileWithoutWend = 29,
+    WendWithoutWhile = 30,
+    /// Microsoft BASIC has no code for this, so it takes the next one after
+    /// `WendWithoutWhile`.
+    LoopWithoutDo = 31,
+    DeviceIo = 57,
+    InputPastEnd = 62,
+}
+
+/// Where something is in a line of source code: the column it starts in,
+/// counting from 1, and the number of characters it takes up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub column: usize,
+    pub width: usize,
+}
+
+/// An error, along with where it happened so that it can be shown to the
+/// user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicError {
+    pub kind: ErrorKind,
+    pub code: ErrorCode,
+    pub message: String,
+
+    /// The number of the program line the error happened in, if any, and
+    /// the index of the statement in it, if known.
+    pub line: Option<i32>,
+    pub statement: Option<usize>,
+
+    /// The text of the line the error happened in, and where in it the
+    /// part that's wrong is, if known.
+    pub source: Option<String>,
+    pub span: Option<Span>,
+}
+
+impl BasicError {
+    pub fn new(kind: ErrorKind, code: ErrorCode, message: impl Into<String>) -> BasicError {
+        BasicError {
+            kind,
+            code,
+            message: message.into(),
+            line: None,
+            statement: None,
+            source: None,
+            span: None,
+        }
+    }
+
+    /// Create a syntax error with the given message.
+    pub fn syntax(message: impl Into<String>) -> BasicError {
+        BasicError::new(ErrorKind::Syntax, ErrorCode::Syntax, message)
+    }
+
+    /// Create a runtime error with the given code and message.
+    pub fn runtime(code: ErrorCode, message: impl Into<String>) -> BasicError {
+        BasicError::new(ErrorKind::Runtime, code, message)
+    }
+
+    /// Create the error that stops a program in the given line, so that
+    /// it can be continued.
+    pub fn stopped(line_num: i32) -> BasicError {
+        BasicError::new(ErrorKind::Break, ErrorCode::Break, "Break").in_line(line_num)
+    }
+
+    /// Note that the program was stopped inside a sub or function, so it
+    /// can't be continued: there's no way back into the middle of the call.
+    pub fn inside_procedure(mut self) -> BasicError {
+        if self.kind == ErrorKind::Break {
+            self.code = ErrorCode::CantContinue;
+            self.message = String::from("Break inside a sub or function");
+        }
+        self
+    }
+
+    /// Note the number of the line the error happened in, unless it's
+    /// already known (e.g. for an error inside a procedure call).
+    pub fn in_line(mut self, line_num: i32) -> BasicError {
+        self.line.get_or_insert(line_num);
+        self
+    }
+
+    /// Note the index of the statement the error happened in within its
+    /// line, unless it's already known.
+    pub fn in_statement(mut self, stmt: usize) -> BasicError {
+        self.statement.get_or_insert(stmt);
+        self
+    }
+
+    /// Note which part of the line the error happened in is wrong, unless
+    /// it's already known (e.g. a part of an expression inside the
+    /// statement being marked).
+    pub fn at(mut self, span: Span) -> BasicError {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    /// Note the text of the line the error happened in, unless it's
+    /// already known.
+    pub fn in_source(mut self, source: &str) -> BasicError {
+        self.source
+            .get_or_insert_with(|| source.trim_end().to_string());
+        self
+    }
+
+    /// Describe the error for the user, showing the line it happened in
+    /// with the wrong part marked, e.g.
+    ///
+    /// ```text
+    /// Syntax error: Unexpected token in line 20
+    ///   |
+    ///   | 20 print 1 +* 2
+    ///   |            ^
+    /// ```
+    pub fn render(&self) -> String {
+        let mut text = match self.kind {
+            ErrorKind::Syntax => format!("Syntax error: {}", self),
+            ErrorKind::Runtime => format!("Runtime error: {}", self),
+            ErrorKind::Break => self.to_string(),
+        };
+
+        if let Some(source) = &self.source {
+            text.push_str(&format!("\n  |\n  | {}", source));
+
+            if let Some(span) = self.span {
+                let indent = " ".repeat(span.column.saturating_sub(1));
+                let carets = "^".repeat(span.width.max(1));
+                text.push_str(&format!("\n  | {}{}", indent, carets));
+            }
+        }
+
+        text
+    }
 }
 
 impl Display for BasicError {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        match self {
-            BasicError::SyntaxError(msg) => write!(f, "{}", msg),
-            BasicError::RuntimeError(msg) => write!(f, "{}", msg),
+        match self.line {
+            Some(line_num) => write!(f, "{} in line {}", self.message, line_num),
+            None => write!(f, "{}", self.message),
         }
     }
 }
+
+impl Error for BasicError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_errors() {
+        let err = BasicError::runtime(ErrorCode::Overflow, "Overflow");
+        assert_eq!(err.render(), "Runtime error: Overflow");
+
+        let err = BasicError::syntax("Unexpected token")
+            .in_line(20)
+            .at(Span {
+                column: 12,
+                width: 1,
+            })
+            .in_source("20 print 1 +* 2\n")
+            .in_line(30)
+            .in_source("30 print");
+        assert_eq!(err.to_string(), "Unexpected token in line 20");
+        assert_eq!(
+            err.render(),
+            "Syntax error: Unexpected token in line 20\n  |\n  | 20 print 1 +* 2\n  |            ^"
+        );
+
+        let err = BasicError::stopped(40)
+            .at(Span {
+                column: 4,
+                width: 4,
+            })
+            .in_source("40 stop");
+        assert_eq!(
+            err.render(),
+            "Break in line 40\n  |\n  | 40 stop\n  |    ^^^^"
+        );
+        assert_eq!((err.kind, err.code as i32), (ErrorKind::Break, 0));
+
+        // Without a span, the line is shown but nothing in it is marked.
+        let err = BasicError::runtime(ErrorCode::Overflow, "Overflow").in_source("print 2^99");
+        assert_eq!(err.render(), "Runtime error: Overflow\n  |\n  | print 2^99");
+    }
+}
@@ -2,190 +2,605 @@ use std::{
     collections::HashMap,
     fs::File,
     io::{BufRead, BufReader, Write},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use crate::{
-    errors::BasicError,
-    expression::Number,
+    errors::{BasicError, ErrorCode, ErrorKind},
+    expression::{
+        Expression, Value, eval_condition, eval_expression, is_string_var, type_mismatch,
+    },
+    function::define_function,
+    number::Number,
+    output,
     parser::SourceReader,
-    program::{find_line, update_program},
-    statement::{ProgramSignal, Statement},
+    program::{Position, Procedure, Program, find_line, find_wend, match_blocks},
+    statement::{ProgramSignal, Statement, find_else, format_statements},
+    variables::Variables,
 };
 
-/// Run a program from the beginning.
+/// How much stack the interpreter runs with. Each nested procedure call
+/// takes a few dozen kilobytes of it in a debug build, so this leaves room
+/// to nest calls as deep as `MAX_DEPTH_LIMIT` allows.
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Set when Ctrl-C is pressed, so that the running program stops before
+/// its next statement.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stop the running program before its next statement, as if it had
+/// reached a `stop`. Called when Ctrl-C is pressed.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Run a program from the beginning. Any variables and arrays left over
+/// from a previous run are cleared first.
 ///
 /// # Arguments
 /// * `program` - The program to run
 /// * `variables` - The variables table
+/// * `continuation` - Where the program is kept up to if it stops
 ///
 pub fn run(
-    variables: &mut HashMap<char, Number>,
-    program: &Vec<(i32, Statement)>,
+    variables: &mut Variables,
+    program: &[(i32, Vec<Statement>)],
+    continuation: &mut Continuation,
+) -> Option<BasicError> {
+    // Block ifs and procedures must be properly matched before the program
+    // can run.
+    let blocks = match match_blocks(program) {
+        Ok(b) => b,
+        Err(e) => return Some(e),
+    };
+
+    variables.reset();
+    *continuation = Continuation::default();
+
+    let mut data: Vec<(i32, Value)> = Vec::new();
+
+    // Functions and procedures defined anywhere in the program can be
+    // called from the start, and all data can be read.
+    for (line_idx, (line_num, statements)) in program.iter().enumerate() {
+        for (stmt_idx, statement) in statements.iter().enumerate() {
+            let pos = Position {
+                line: line_idx,
+                stmt: stmt_idx,
+            };
+
+            let res = match statement {
+                Statement::Def(name, params, body) => {
+                    define_function(name, params, body, variables)
+                }
+                Statement::Sub(name, params) | Statement::Function(name, params) => {
+                    define_procedure(name, params, statement, pos, &blocks, variables, program)
+                }
+                Statement::Data(values) => {
+                    data.extend(values.iter().map(|v| (*line_num, v.clone())));
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+
+            if let Err(e) = res {
+                return Some(in_line(e, program, pos));
+            }
+        }
+    }
+
+    variables.set_data(data);
+
+    run_from(
+        variables,
+        program,
+        &blocks,
+        continuation,
+        Execution::default(),
+    )
+}
+
+/// Carry on running a program that was stopped by `stop` or an error,
+/// from where it stopped.
+///
+/// # Arguments
+/// * `program` - The program to continue
+/// * `variables` - The variables table
+/// * `continuation` - Where the program was up to when it stopped
+///
+pub fn cont(
+    variables: &mut Variables,
+    program: &[(i32, Vec<Statement>)],
+    continuation: &mut Continuation,
+) -> Option<BasicError> {
+    let execution = match continuation.take() {
+        Ok(e) => e,
+        Err(e) => return Some(e),
+    };
+
+    let blocks = match match_blocks(program) {
+        Ok(b) => b,
+        Err(e) => return Some(e),
+    };
+
+    run_from(variables, program, &blocks, continuation, execution)
+}
+
+/// Run a program from the given point. If anything stops it before the
+/// end, where it was up to is kept so that it can be continued.
+fn run_from(
+    variables: &mut Variables,
+    program: &[(i32, Vec<Statement>)],
+    blocks: &HashMap<Position, Position>,
+    continuation: &mut Continuation,
+    mut execution: Execution,
 ) -> Option<BasicError> {
-    let mut pc = 0;
+    if let Some(e) = run_lines(variables, program, blocks, &mut execution, true) {
+        if e.code != ErrorCode::CantContinue {
+            continuation.stop(execution);
+        }
+        return Some(e);
+    }
+
+    output::end_line();
+    println!("Ready.");
+
+    None
+}
+
+/// Where a program's execution is up to: the program counter, the call
+/// stack and the loops currently running.
+#[derive(Debug, Default)]
+pub struct Execution {
+    pc: Position,
+    stack: Vec<Position>,
+    loop_stack: Vec<(String, Number, Number, Position)>,
+    cond_loop_stack: Vec<Position>,
+}
+
+/// Where a stopped program was up to, kept between the lines typed at the
+/// prompt so that it can be continued, and whether that's no longer
+/// possible because the program has changed.
+#[derive(Debug, Default)]
+pub struct Continuation {
+    stopped: Option<Execution>,
+    changed: bool,
+}
+
+impl Continuation {
+    /// Keep where a program was up to when it stopped, so that it can be
+    /// continued.
+    fn stop(&mut self, execution: Execution) {
+        self.stopped = Some(execution);
+        self.changed = false;
+    }
+
+    /// Get where a stopped program was up to, so it can carry on from
+    /// there.
+    fn take(&mut self) -> Result<Execution, BasicError> {
+        match self.stopped.take() {
+            Some(execution) => Ok(execution),
+            None if self.changed => Err(BasicError::runtime(
+                ErrorCode::CantContinue,
+                "Can't continue after the program has been changed",
+            )),
+            None => Err(BasicError::runtime(
+                ErrorCode::CantContinue,
+                "Can't continue",
+            )),
+        }
+    }
+
+    /// Note that the program has been changed, so a stopped one can no
+    /// longer be continued.
+    pub fn program_changed(&mut self) {
+        if self.stopped.take().is_some() {
+            self.changed = true;
+        }
+    }
+}
+
+/// Run the lines of a program (or of a procedure) from the start until
+/// the end is reached or something stops it.
+///
+/// # Arguments
+/// * `variables` - The variables table
+/// * `program` - The lines to run
+/// * `blocks` - The matching parts of each block if in the lines
+/// * `execution` - Where execution is up to, which is updated as it goes
+/// * `trap_errors` - Whether errors can be passed to a handler set with
+///   `on error goto`, which is only in the program itself
+fn run_lines(
+    variables: &mut Variables,
+    program: &[(i32, Vec<Statement>)],
+    blocks: &HashMap<Position, Position>,
+    execution: &mut Execution,
+    trap_errors: bool,
+) -> Option<BasicError> {
+    let Execution {
+        pc,
+        stack,
+        loop_stack,
+        cond_loop_stack,
+    } = execution;
     let mut running = true;
-    let mut stack: Vec<usize> = Vec::new();
-    let mut loop_stack: Vec<(char, i32, i32, usize)> = Vec::new();
 
     let program_size = program.len();
 
     // Execution will continue until the PC reaches the last line or something
     // alters running status (e.g. the 'end' command).
-    while pc < program_size && running == true {
-        let s = program.get(pc).unwrap();
-        match execute_indirect(
-            &s.1,
-            &mut pc,
+    while pc.line < program_size && running {
+        let line = &program[pc.line].1;
+
+        // Move on to the next line once all statements in this one are done.
+        let Some(s) = line.get(pc.stmt) else {
+            *pc = pc.next_line();
+            continue;
+        };
+
+        // Stop if Ctrl-C has been pressed. The program can be continued
+        // from this statement.
+        if INTERRUPTED.swap(false, Ordering::SeqCst) {
+            return Some(BasicError::stopped(program[pc.line].0).in_statement(pc.stmt));
+        }
+
+        let Some(e) = execute_indirect(
+            s,
+            pc,
             &mut running,
             variables,
-            &mut stack,
-            &mut loop_stack,
-            &program,
-        ) {
-            None => (),
-            Some(e) => return Some(e),
+            stack,
+            loop_stack,
+            cond_loop_stack,
+            blocks,
+            program,
+        ) else {
+            continue;
+        };
+        let e = in_line(e, program, *pc);
+
+        // Jump to the error handler, if there is one and it isn't already
+        // dealing with an error. Otherwise, the error stops the program. A
+        // break always stops it.
+        let handler = match variables.error_handler() {
+            Some(h) if trap_errors && e.kind != ErrorKind::Break => h,
+            _ => return Some(e),
+        };
+
+        match find_line(program, handler) {
+            Some(handler_line) => {
+                variables.trap_error(e.code as i32, program[pc.line].0, *pc);
+                *pc = Position::start_of(handler_line);
+            }
+            None => {
+                return Some(in_line(
+                    BasicError::runtime(
+                        ErrorCode::UndefinedLine,
+                        format!("Unknown line number {}", handler),
+                    ),
+                    program,
+                    *pc,
+                ));
+            }
         }
     }
 
-    println!("Ready.");
-
     None
 }
 
-/// Execute a single statement immediately.
+/// Store the procedure defined at the given position of a program, so it
+/// can be called.
+fn define_procedure(
+    name: &str,
+    params: &[String],
+    statement: &Statement,
+    pos: Position,
+    blocks: &HashMap<Position, Position>,
+    variables: &mut Variables,
+    program: &[(i32, Vec<Statement>)],
+) -> Result<(), BasicError> {
+    if variables.get_procedure(name).is_some() {
+        return Err(BasicError::syntax(format!(
+            "Duplicate definition of {}",
+            name
+        )));
+    }
+
+    let end = match blocks.get(&pos) {
+        Some(end) => *end,
+        None => {
+            return Err(BasicError::syntax(format!(
+                "No end to definition of {}",
+                name
+            )));
+        }
+    };
+
+    let is_function = matches!(statement, Statement::Function(_, _));
+    let procedure = Procedure::new(program, pos, end, params, is_function)?;
+    variables.define_procedure(name, procedure);
+
+    Ok(())
+}
+
+/// Call a procedure defined with `sub` or `function`. The arguments are
+/// evaluated first, then the procedure's lines are run with its
+/// parameters set as local variables.
 ///
 /// # Arguments
-/// * `statement` - The statement to execute
+/// * `name` - Name of the procedure
+/// * `args` - Arguments to the procedure
+/// * `variables` - The variables table
+///
+/// # Returns
+/// * The value returned by a function, or None for a sub
+pub fn call_procedure(
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+) -> Result<Option<Value>, BasicError> {
+    let procedure = match variables.get_procedure(name) {
+        Some(p) => p,
+        None => {
+            return Err(BasicError::runtime(
+                ErrorCode::UndefinedFunction,
+                format!("Undefined sub or function {}", name),
+            ));
+        }
+    };
+
+    if args.len() != procedure.params.len() {
+        return Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Incorrect number of arguments to {}", name),
+        ));
+    }
+
+    // Set up the parameters (and a function's result) as local variables.
+    let mut frame: HashMap<String, Value> = HashMap::new();
+
+    for (param, arg) in procedure.params.iter().zip(args.iter()) {
+        let val = eval_expression(arg, variables)?;
+        if matches!(val, Value::String(_)) != is_string_var(param) {
+            return Err(type_mismatch());
+        }
+        frame.insert(param.clone(), val);
+    }
+
+    if procedure.is_function {
+        let initial = match is_string_var(name) {
+            true => Value::String(String::new()),
+            false => Value::Number(Number::Integer(0)),
+        };
+        frame.insert(name.to_string(), initial);
+    }
+
+    variables.push_frame(frame)?;
+    let err = run_lines(
+        variables,
+        &procedure.lines,
+        &procedure.blocks,
+        &mut Execution::default(),
+        false,
+    );
+    let frame = variables.pop_frame();
+
+    if let Some(e) = err {
+        return Err(e.inside_procedure());
+    }
+
+    match procedure.is_function {
+        true => Ok(frame.and_then(|mut f| f.remove(name))),
+        false => Ok(None),
+    }
+}
+
+/// Execute the statements of a line immediately.
+///
+/// # Arguments
+/// * `statements` - The statements to execute
 /// * `variables` - The variable table
 /// * `program` - The current state of the program
+/// * `continuation` - Where the program was up to if it was stopped
 pub fn execute_immediate(
-    statement: &Statement,
-    variables: &mut HashMap<char, Number>,
-    program: &mut Vec<(i32, Statement)>,
+    statements: &[Statement],
+    variables: &mut Variables,
+    program: &mut Program,
+    continuation: &mut Continuation,
 ) -> Option<BasicError> {
-    match statement.execute(variables) {
-        Err(e) => Some(e),
+    // Forget any Ctrl-C pressed at the prompt, so it doesn't stop a
+    // program, or a function called from an expression, run by this line.
+    INTERRUPTED.store(false, Ordering::SeqCst);
 
-        // Deal with any signal that came back.
-        Ok(signal) => match signal {
-            None => None,
+    let mut idx = 0;
 
-            // List the program
-            Some(ProgramSignal::List) => {
-                for line in program.iter() {
-                    println!("{} {}", line.0, line.1);
+    while let Some(statement) = statements.get(idx) {
+        let err = match statement.execute(variables) {
+            Err(e) => Some(e),
+
+            // Skip the rest of the line (e.g. the else part of an if).
+            Ok(Some(ProgramSignal::SkipLine)) => return None,
+
+            // A false if condition skips to its else part, if any.
+            Ok(Some(ProgramSignal::SkipToElse)) => match find_else(statements, idx) {
+                Some(else_idx) => {
+                    idx = else_idx + 1;
+                    continue;
                 }
+                None => return None,
+            },
 
-                None
+            // Deal with any other signal that came back.
+            Ok(signal) => handle_immediate_signal(signal, variables, program, continuation),
+        };
+
+        if err.is_some() {
+            return err;
+        }
+
+        idx += 1;
+    }
+
+    None
+}
+
+/// Carry out the action signalled by a statement executed in immediate mode.
+fn handle_immediate_signal(
+    signal: Option<ProgramSignal>,
+    variables: &mut Variables,
+    program: &mut Program,
+    continuation: &mut Continuation,
+) -> Option<BasicError> {
+    match signal {
+        None => None,
+
+        // List the program
+        Some(ProgramSignal::List) => {
+            for line in program.lines.iter() {
+                println!("{} {}", line.0, format_statements(&line.1));
             }
 
-            // Run the program.
-            Some(ProgramSignal::Run) => run(variables, program),
-
-            // Load a program from file.
-            Some(ProgramSignal::Load(filename)) => {
-                let src_file = match File::open(filename) {
-                    Ok(file) => file,
-                    Err(err) => return Some(BasicError::RuntimeError(format!("{}", err))),
-                };
-
-                let reader = BufReader::new(src_file);
-
-                // Read the source file line by line...
-                for line in reader.lines() {
-                    match line {
-                        Err(err) => {
-                            return Some(BasicError::RuntimeError(format!(
-                                "File read error: {}",
-                                err
-                            )));
-                        }
-                        // ...and build each one into a program line.
-                        Ok(src_line) => {
-                            let mut reader = SourceReader::new(src_line.clone());
-                            reader.skip_ws();
-
-                            // Get line number
-                            if !reader.is_digit() {
-                                return Some(BasicError::RuntimeError(String::from(
-                                    "Line number missing in file",
-                                )));
-                            }
+            None
+        }
 
-                            let line_num = match reader.get_integer() {
-                                Err(e) => {
-                                    return Some(e);
-                                }
-                                Ok(n) => n,
-                            };
+        // Run the program.
+        Some(ProgramSignal::Run) => run(variables, &program.lines, continuation),
 
-                            // Build the line
-                            let line: (i32, Statement) = match reader.build_statement() {
-                                Ok(s) => (line_num, s),
-                                Err(e) => {
-                                    return Some(e);
-                                }
-                            };
+        // Carry on running a stopped program.
+        Some(ProgramSignal::Cont) => cont(variables, &program.lines, continuation),
 
-                            // Update the program
-                            update_program(program, line);
-                        }
-                    }
+        // Load a program from file.
+        Some(ProgramSignal::Load(filename)) => {
+            let src_file = match File::open(filename) {
+                Ok(file) => file,
+                Err(err) => {
+                    return Some(BasicError::runtime(ErrorCode::DeviceIo, format!("{}", err)));
                 }
+            };
 
-                println!("File loaded.");
+            let reader = BufReader::new(src_file);
 
-                None
-            }
+            // Loading changes the program, so a stopped one can't be
+            // continued any more.
+            continuation.program_changed();
 
-            // Save the program to file.
-            Some(ProgramSignal::Save(filename)) => {
-                let mut file = match File::create(filename) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        return Some(BasicError::RuntimeError(format!("File read error: {}", e)));
+            // Read the source file line by line...
+            for line in reader.lines() {
+                match line {
+                    Err(err) => {
+                        return Some(BasicError::runtime(
+                            ErrorCode::DeviceIo,
+                            format!("File read error: {}", err),
+                        ));
                     }
-                };
-
-                // Print each line to the file.
-                for line in program.iter() {
-                    match writeln!(file, "{} {}", line.0, line.1) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            return Some(BasicError::RuntimeError(format!(
-                                "File read error: {}",
-                                e
-                            )));
+                    // ...and build each one into a program line.
+                    Ok(src_line) => {
+                        let mut reader = SourceReader::new(src_line.clone());
+                        reader.skip_ws();
+
+                        // Get line number
+                        if !reader.is_digit() {
+                            return Some(BasicError::runtime(
+                                ErrorCode::IllegalFunctionCall,
+                                "Line number missing in file",
+                            ));
                         }
+
+                        let line_num = match reader.get_integer() {
+                            Err(e) => {
+                                return Some(e);
+                            }
+                            Ok(n) => n,
+                        };
+
+                        // Build the line
+                        let line: (i32, Vec<Statement>) = match reader.build_line() {
+                            Ok(s) => (line_num, s),
+                            Err(e) => {
+                                return Some(e.in_line(line_num));
+                            }
+                        };
+
+                        // Update the program
+                        program.update(line, &src_line, reader.statement_spans());
                     }
                 }
+            }
 
-                println!("File saved.");
-
-                None
+            // Report any mismatched block ifs in the loaded program.
+            if let Err(e) = match_blocks(&program.lines) {
+                return Some(e);
             }
 
-            // Clear all variables
-            Some(ProgramSignal::ClearVars) => {
-                variables.clear();
+            println!("File loaded.");
+
+            None
+        }
 
-                None
+        // Save the program to file.
+        Some(ProgramSignal::Save(filename)) => {
+            let mut file = match File::create(filename) {
+                Ok(f) => f,
+                Err(e) => {
+                    return Some(BasicError::runtime(
+                        ErrorCode::DeviceIo,
+                        format!("File read error: {}", e),
+                    ));
+                }
+            };
+
+            // Print each line to the file.
+            for line in program.lines.iter() {
+                match writeln!(file, "{} {}", line.0, format_statements(&line.1)) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Some(BasicError::runtime(
+                            ErrorCode::DeviceIo,
+                            format!("File read error: {}", e),
+                        ));
+                    }
+                }
             }
 
-            // These actions cannot be performed in immediate mode.
-            Some(ProgramSignal::Jump(_))
-            | Some(ProgramSignal::Call(_))
-            | Some(ProgramSignal::Return)
-            | Some(ProgramSignal::StartLoop(_, _, _, _))
-            | Some(ProgramSignal::EndLoop)
-            | Some(ProgramSignal::End) => Some(BasicError::RuntimeError(String::from(
-                "Cannot execute this command outside of a program",
-            ))),
-        },
+            println!("File saved.");
+
+            None
+        }
+
+        // Clear all variables
+        Some(ProgramSignal::ClearVars) => {
+            variables.clear();
+
+            None
+        }
+
+        // These actions cannot be performed in immediate mode.
+        Some(ProgramSignal::Jump(_))
+        | Some(ProgramSignal::SkipLine)
+        | Some(ProgramSignal::SkipToElse)
+        | Some(ProgramSignal::BlockIf(_))
+        | Some(ProgramSignal::SkipToEndIf)
+        | Some(ProgramSignal::EndIf)
+        | Some(ProgramSignal::Call(_))
+        | Some(ProgramSignal::Return)
+        | Some(ProgramSignal::StartLoop(_, _, _, _))
+        | Some(ProgramSignal::EndLoop(_))
+        | Some(ProgramSignal::SkipProcedure)
+        | Some(ProgramSignal::EndProcedure)
+        | Some(ProgramSignal::StartWhile(_))
+        | Some(ProgramSignal::EndWhile)
+        | Some(ProgramSignal::StartDo)
+        | Some(ProgramSignal::EndDo(_))
+        | Some(ProgramSignal::Resume(_))
+        | Some(ProgramSignal::ResumeNext)
+        | Some(ProgramSignal::Stop)
+        | Some(ProgramSignal::End) => Some(BasicError::runtime(
+            ErrorCode::IllegalDirect,
+            "Cannot execute this command outside of a program",
+        )),
     }
 }
 
-/// Execute a line as part of a running program.
+/// Execute a statement as part of a running program.
 ///
 /// # Arguments
 /// * `statement` - The statement to execute
@@ -193,174 +608,396 @@ pub fn execute_immediate(
 /// * `running` - Flag indicating whether program is currently running
 /// * `variables` - The variable table
 /// * `stack` -  The call stack
+/// * `loop_stack` - The for loops currently running
+/// * `cond_loop_stack` - The positions of the while and do loops currently
+///   running
+/// * `blocks` - The matching parts of each block if, and the end of each
+///   loop
 /// * `program` - The program being executed
+#[allow(clippy::too_many_arguments)]
 pub fn execute_indirect(
     statement: &Statement,
-    pc: &mut usize,
+    pc: &mut Position,
     running: &mut bool,
-    variables: &mut HashMap<char, Number>,
-    stack: &mut Vec<usize>,
-    loop_stack: &mut Vec<(char, i32, i32, usize)>,
-    program: &Vec<(i32, Statement)>,
+    variables: &mut Variables,
+    stack: &mut Vec<Position>,
+    loop_stack: &mut Vec<(String, Number, Number, Position)>,
+    cond_loop_stack: &mut Vec<Position>,
+    blocks: &HashMap<Position, Position>,
+    program: &[(i32, Vec<Statement>)],
 ) -> Option<BasicError> {
     match statement.execute(variables) {
         Ok(maybe_flow) => match maybe_flow {
             None => {
-                *pc += 1;
+                *pc = pc.next_statement();
             }
 
             // Deal with any signal that came back.
             Some(f) => match f {
                 // Jump to another line in the program.
-                ProgramSignal::Jump(line_num) => match find_line(&program, line_num) {
+                ProgramSignal::Jump(line_num) => match find_line(program, line_num) {
                     Some(new_line) => {
-                        *pc = new_line;
+                        let from = *pc;
+                        *pc = Position::start_of(new_line);
+
+                        // Jumping out of a while or do loop leaves it, and
+                        // so does jumping out of a for loop, whether back
+                        // before its for or on past its next.
+                        let leaves = |start: &Position| {
+                            leaves_loop(*start, blocks.get(start).copied(), from, *pc)
+                        };
+                        cond_loop_stack.retain(|start| !leaves(start));
+                        loop_stack.retain(|entry| !leaves(&entry.3));
+                    }
+                    None => {
+                        return Some(BasicError::runtime(
+                            ErrorCode::UndefinedLine,
+                            format!("Unknown line number {}", line_num),
+                        ));
+                    }
+                },
+
+                // Skip the rest of the line.
+                ProgramSignal::SkipLine => {
+                    *pc = pc.next_line();
+                }
+
+                // Skip to the statement after the if's else, or to the next
+                // line if there's no else.
+                ProgramSignal::SkipToElse => match find_else(&program[pc.line].1, pc.stmt) {
+                    Some(else_idx) => {
+                        *pc = Position {
+                            line: pc.line,
+                            stmt: else_idx + 1,
+                        }
                     }
                     None => {
-                        let bad_line = program.get(*pc).expect("Unrecoverable error");
-                        return Some(BasicError::RuntimeError(format!(
-                            "Unknown line number in line {}",
-                            bad_line.0
-                        )));
+                        *pc = pc.next_line();
                     }
                 },
 
+                // Enter a block if's first branch when its condition is
+                // true. Otherwise, try each following elseif in turn until
+                // one is true, or an else or end if is reached.
+                ProgramSignal::BlockIf(taken) => {
+                    let mut part = *pc;
+                    let mut taken = taken;
+
+                    while !taken {
+                        part = match blocks.get(&part) {
+                            Some(p) => *p,
+                            None => break,
+                        };
+
+                        taken = match &program[part.line].1[part.stmt] {
+                            Statement::ElseIf(condition) => {
+                                match eval_condition(condition, variables) {
+                                    Ok(t) => t,
+                                    Err(e) => return Some(in_line(e, program, part)),
+                                }
+                            }
+                            _ => true,
+                        };
+                    }
+
+                    *pc = part.next_statement();
+                }
+
+                // A branch of a block if has finished, so skip over the
+                // remaining parts to the end if.
+                ProgramSignal::SkipToEndIf => {
+                    let mut part = *pc;
+
+                    while let Some(p) = blocks.get(&part) {
+                        part = *p;
+                    }
+
+                    *pc = part.next_statement();
+                }
+
+                ProgramSignal::EndIf => {
+                    *pc = pc.next_statement();
+                }
+
                 // Call a subroutine
                 ProgramSignal::Call(line_num) => {
                     // Push current location to the stack for later return
                     stack.push(*pc);
 
-                    match find_line(&program, line_num) {
-                        Some(new_line) => *pc = new_line,
+                    match find_line(program, line_num) {
+                        Some(new_line) => *pc = Position::start_of(new_line),
                         None => {
-                            let bad_line = program.get(*pc).expect("Unrecoverable error");
-                            return Some(BasicError::RuntimeError(format!(
-                                "Unknown line number in line {}",
-                                bad_line.0
-                            )));
+                            return Some(BasicError::runtime(
+                                ErrorCode::UndefinedLine,
+                                format!("Unknown line number {}", line_num),
+                            ));
                         }
                     }
                 }
 
                 // Return from a subroutine. Pop the address from the stack to return
-                // to the original location, then increment to move to next line.
+                // to the original location, then move on to the next statement.
                 ProgramSignal::Return => match stack.pop() {
                     Some(address) => {
-                        *pc = address;
-                        *pc += 1;
+                        *pc = address.next_statement();
                     }
                     None => {
-                        let bad_line = program.get(*pc).expect("Unrecoverable error");
-                        return Some(BasicError::RuntimeError(format!(
-                            "Return without gosub in line {}",
-                            bad_line.0
-                        )));
+                        return Some(BasicError::runtime(
+                            ErrorCode::ReturnWithoutGosub,
+                            "Return without gosub",
+                        ));
                     }
                 },
 
                 // Start a loop, storing the loop context to the loop stack.
                 ProgramSignal::StartLoop(var, start_val, end_val, maybe_step_val) => {
-                    // Are we entering the loop (i.e. is the var at the top of the
-                    // loop stack different from that in the current line? If so,
-                    // set up the stack entry.
-
                     // If there's no step value. we default to 1. Exception: if
                     // end_val < start_val, make step = -1.
                     let step_val = match maybe_step_val {
                         Some(v) => v,
                         None => {
                             if end_val < start_val {
-                                -1
+                                Number::Integer(-1)
                             } else {
-                                1
+                                Number::Integer(1)
                             }
                         }
                     };
 
                     // Step value can't be zero.
-                    if step_val == 0 {
-                        return Some(BasicError::RuntimeError(String::from(
+                    if step_val == Number::Integer(0) {
+                        return Some(BasicError::runtime(
+                            ErrorCode::IllegalFunctionCall,
                             "Step value cannot be zero",
-                        )));
+                        ));
                     }
 
-                    // Stack entry: (char, i32, i32, usize) =
-                    // variable, end_val, step_val, PC at for statement
-                    if loop_stack.is_empty()
-                        || loop_stack.last().expect("Error executing for").0 != var
-                    {
-                        variables.insert(var, Number::Integer(start_val));
-                        loop_stack.push((var, end_val, step_val, *pc));
+                    // A for always starts its loop afresh. If this for's loop
+                    // is still on the stack, it was left without finishing, so
+                    // drop it along with any loops inside it.
+                    if let Some(idx) = loop_stack.iter().position(|entry| entry.3 == *pc) {
+                        loop_stack.truncate(idx);
                     }
 
-                    *pc += 1;
+                    if let Err(e) = variables.set(&var, Value::Number(start_val)) {
+                        return Some(e);
+                    }
+
+                    // Stack entry: (String, Number, Number, Position) =
+                    // variable, end_val, step_val, PC at for statement
+                    loop_stack.push((var, end_val, step_val, *pc));
+
+                    *pc = pc.next_statement();
                 }
 
                 // End of loop reached. Decide whether to loop again or
-                // exit the loop.
-                ProgramSignal::EndLoop => {
-                    // Increment the loop variable.
-                    match loop_stack.last() {
-                        None => {
-                            return Some(BasicError::RuntimeError(String::from(
-                                "Next without for",
-                            )));
-                        }
-                        Some(entry) => {
-                            variables.insert(
-                                entry.0,
-                                variables[&entry.0.clone()] + Number::Integer(entry.2),
-                            );
-
-                            // Has it reached the end val? Positive stepping
-                            // means we must be above the end val; negative
-                            // stepping means we must be below the end val.
-                            let end_reached = match entry.2.is_negative() {
-                                true => variables[&entry.0] < Number::Integer(entry.1),
-                                false => variables[&entry.0] > Number::Integer(entry.1),
+                // exit the loop. A next that names several variables, e.g.
+                // `next j, i`, works like a next for each in turn.
+                ProgramSignal::EndLoop(vars) => {
+                    let names = match vars.is_empty() {
+                        true => vec![None],
+                        false => vars.iter().map(Some).collect(),
+                    };
+                    let mut next_pc = pc.next_statement();
+
+                    for name in names {
+                        // Find the loop to end: the innermost one, or the
+                        // innermost with the given variable.
+                        let found = match name {
+                            None => loop_stack.len().checked_sub(1),
+                            Some(n) => loop_stack.iter().rposition(|entry| &entry.0 == n),
+                        };
+
+                        let Some(idx) = found else {
+                            let message = match loop_stack.is_empty() {
+                                true => "Next without for",
+                                false => "Wrong next variable",
                             };
+                            return Some(BasicError::runtime(ErrorCode::NextWithoutFor, message));
+                        };
 
-                            // If it's reached the end val, then pop the loop stack and
-                            // proceed to next line.
-                            if end_reached {
-                                loop_stack.pop();
-                                *pc += 1;
-                            } else {
-                                // Otherwise, jump to the top of the loop.
-                                *pc = entry.3;
+                        // Any loops inside this one were jumped out of.
+                        loop_stack.truncate(idx + 1);
+                        let entry = &loop_stack[idx];
+
+                        // Increment the loop variable.
+                        let current = match variables.get(&entry.0) {
+                            Some(Value::Number(n)) => n.clone(),
+                            _ => Number::Integer(0),
+                        };
+                        let next_val = match current + entry.2.clone() {
+                            Ok(n) => n,
+                            Err(e) => return Some(e),
+                        };
+                        if let Err(e) = variables.set(&entry.0, Value::Number(next_val.clone())) {
+                            return Some(e);
+                        }
+
+                        // If it's reached the end val, then pop the loop stack and
+                        // go on to the next variable, if any. Otherwise, jump to
+                        // the statement after the for.
+                        if loop_finished(&next_val, &entry.1, &entry.2) {
+                            loop_stack.pop();
+                        } else {
+                            next_pc = entry.3.next_statement();
+                            break;
+                        }
+                    }
+
+                    *pc = next_pc;
+                }
+
+                // Enter a while loop, remembering where it starts. If its
+                // condition is false, skip past the matching wend instead.
+                ProgramSignal::StartWhile(enter) => {
+                    if enter {
+                        cond_loop_stack.push(*pc);
+                        *pc = pc.next_statement();
+                    } else {
+                        match find_wend(program, *pc) {
+                            Some(wend) => *pc = wend.next_statement(),
+                            None => {
+                                return Some(BasicError::runtime(
+                                    ErrorCode::WhileWithoutWend,
+                                    "While without wend",
+                                ));
                             }
                         }
                     }
                 }
 
+                // Go back to the while to test its condition again.
+                ProgramSignal::EndWhile => match cond_loop_stack.last() {
+                    Some(start)
+                        if matches!(statement_at(program, *start), Some(Statement::While(_))) =>
+                    {
+                        *pc = *start;
+                        cond_loop_stack.pop();
+                    }
+                    _ => {
+                        return Some(BasicError::runtime(
+                            ErrorCode::WendWithoutWhile,
+                            "Wend without while",
+                        ));
+                    }
+                },
+
+                // Enter a do loop, remembering where it starts.
+                ProgramSignal::StartDo => {
+                    cond_loop_stack.push(*pc);
+                    *pc = pc.next_statement();
+                }
+
+                // Either go back to the statement after the do, or leave
+                // the loop.
+                ProgramSignal::EndDo(repeat) => match cond_loop_stack.last() {
+                    Some(start) if matches!(statement_at(program, *start), Some(Statement::Do)) => {
+                        if repeat {
+                            *pc = start.next_statement();
+                        } else {
+                            cond_loop_stack.pop();
+                            *pc = pc.next_statement();
+                        }
+                    }
+                    _ => {
+                        return Some(BasicError::runtime(
+                            ErrorCode::LoopWithoutDo,
+                            "Loop without do",
+                        ));
+                    }
+                },
+
+                // Skip over a procedure definition.
+                ProgramSignal::SkipProcedure => match blocks.get(pc) {
+                    Some(end) => *pc = end.next_statement(),
+                    None => *pc = pc.next_line(),
+                },
+
+                // The end of a procedure is only reached by running into
+                // it from outside (e.g. with a goto), since calls stop
+                // just before it.
+                ProgramSignal::EndProcedure => {
+                    return Some(BasicError::runtime(
+                        ErrorCode::IllegalFunctionCall,
+                        "End of sub or function reached outside of a call",
+                    ));
+                }
+
                 // Clear all variables.
                 ProgramSignal::ClearVars => {
                     variables.clear();
-                    *pc += 1;
+                    *pc = pc.next_statement();
+                }
+
+                // Finish handling an error, going back to the statement
+                // where it happened, on to the statement after it, or to
+                // another line.
+                ProgramSignal::Resume(_) | ProgramSignal::ResumeNext => {
+                    let Some(error_pc) = variables.resume() else {
+                        return Some(BasicError::runtime(
+                            ErrorCode::ResumeWithoutError,
+                            "Resume without error",
+                        ));
+                    };
+
+                    match f {
+                        ProgramSignal::Resume(Some(line_num)) => {
+                            match find_line(program, line_num) {
+                                Some(new_line) => *pc = Position::start_of(new_line),
+                                None => {
+                                    return Some(BasicError::runtime(
+                                        ErrorCode::UndefinedLine,
+                                        format!("Unknown line number {}", line_num),
+                                    ));
+                                }
+                            }
+                        }
+                        ProgramSignal::ResumeNext => *pc = error_pc.next_statement(),
+                        _ => *pc = error_pc,
+                    }
                 }
 
                 ProgramSignal::List => {
-                    return Some(BasicError::RuntimeError(String::from(
+                    return Some(BasicError::runtime(
+                        ErrorCode::IllegalFunctionCall,
                         "Cannot list a program during execution",
-                    )));
+                    ));
                 }
 
                 ProgramSignal::Run => {
-                    return Some(BasicError::RuntimeError(String::from(
+                    return Some(BasicError::runtime(
+                        ErrorCode::IllegalFunctionCall,
                         "Cannot run a program that's already in execution",
-                    )));
+                    ));
+                }
+
+                ProgramSignal::Cont => {
+                    return Some(BasicError::runtime(
+                        ErrorCode::IllegalFunctionCall,
+                        "Cannot continue a program that's already in execution",
+                    ));
+                }
+
+                // Stop the program, so that it can be continued from the
+                // next statement.
+                ProgramSignal::Stop => {
+                    let error = BasicError::stopped(program[pc.line].0).in_statement(pc.stmt);
+                    *pc = pc.next_statement();
+                    return Some(error);
                 }
 
                 ProgramSignal::Load(_) => {
-                    return Some(BasicError::RuntimeError(String::from(
+                    return Some(BasicError::runtime(
+                        ErrorCode::IllegalFunctionCall,
                         "Cannot load a program during execution",
-                    )));
+                    ));
                 }
 
                 ProgramSignal::Save(_) => {
-                    return Some(BasicError::RuntimeError(String::from(
+                    return Some(BasicError::runtime(
+                        ErrorCode::IllegalFunctionCall,
                         "Cannot save a program during execution",
-                    )));
+                    ));
                 }
 
                 ProgramSignal::End => {
@@ -368,53 +1005,106 @@ pub fn execute_indirect(
                 }
             },
         },
-        Err(e) => match e {
-            // Wrap errors to give line number info to user.
-            BasicError::SyntaxError(e) => {
-                let line = program.get(*pc).expect("Error").0;
-                return Some(BasicError::SyntaxError(format!("{} in line {}", e, line)));
-            }
-            BasicError::RuntimeError(e) => {
-                let line = program.get(*pc).expect("Error").0;
-                return Some(BasicError::RuntimeError(format!("{} in line {}", e, line)));
-            }
-        },
+        Err(e) => return Some(e),
     }
 
     None
 }
 
+/// How far a float loop variable can pass its end value, as a fraction of
+/// the step, and still count as reaching it. This lets `for x = 0 to 0.3
+/// step 0.1` include 0.3, even though adding up the steps gives a little
+/// more.
+const LOOP_TOLERANCE: f64 = 1e-9;
+
+/// Has a for loop's variable gone past its end value? Positive stepping
+/// means it must be above the end value; negative stepping means it must be
+/// below it. Integers are compared exactly, while floats get some leeway
+/// for rounding errors.
+fn loop_finished(value: &Number, end_val: &Number, step_val: &Number) -> bool {
+    let negative = *step_val < Number::Integer(0);
+
+    if value.is_int() && end_val.is_int() && step_val.is_int() {
+        return match negative {
+            true => value < end_val,
+            false => value > end_val,
+        };
+    }
+
+    let slack = step_val.float_value().abs() * LOOP_TOLERANCE;
+
+    match negative {
+        true => value.float_value() < end_val.float_value() - slack,
+        false => value.float_value() > end_val.float_value() + slack,
+    }
+}
+
+/// Does a jump leave the loop that starts and ends at the given
+/// positions? It does when it goes from inside the loop to somewhere
+/// outside it. A loop with no end runs to the end of the program.
+fn leaves_loop(start: Position, end: Option<Position>, from: Position, to: Position) -> bool {
+    let inside = |pos: Position| pos > start && end.is_none_or(|end| pos <= end);
+    inside(from) && !inside(to)
+}
+
+/// Get the statement at the given position in the program, if any.
+fn statement_at(program: &[(i32, Vec<Statement>)], pos: Position) -> Option<&Statement> {
+    program.get(pos.line).and_then(|line| line.1.get(pos.stmt))
+}
+
+/// Add where an error happened to it: the number of the line and which
+/// statement in it failed. Errors from inside a procedure call already say
+/// where in the procedure they happened, and a break already says where
+/// the program stopped, so they're left as they are.
+fn in_line(error: BasicError, program: &[(i32, Vec<Statement>)], pos: Position) -> BasicError {
+    match program.get(pos.line) {
+        Some((line_num, _)) if error.line.is_none() => {
+            error.in_line(*line_num).in_statement(pos.stmt)
+        }
+        _ => error,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{errors::Span, variables::MAX_DEPTH_LIMIT};
+    use std::thread;
 
-    #[test]
-    fn jumps_correctly() {
-        let program_src = vec![
-            "5 let a=3 / 1",
-            "10 goto 30",
-            "20 let a = 2 / 1",
-            "30 print a",
-        ];
-
-        let mut program: Vec<(i32, Statement)> = Vec::new();
-        let mut variables: HashMap<char, Number> = HashMap::new();
+    /// Parse the given numbered lines into a program.
+    fn parse_program(program_src: &[&str]) -> Vec<(i32, Vec<Statement>)> {
+        let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
 
         for src_line in program_src.iter() {
             let mut reader = SourceReader::new(src_line.to_string());
-            let line_num = reader.get_integer().expect("Error letting line number");
+            let line_num = reader.get_integer().expect("Error getting line number");
 
-            match reader.build_statement() {
+            match reader.build_line() {
                 Ok(res) => program.push((line_num, res)),
                 Err(e) => panic!("{}", e),
             }
         }
 
-        match run(&mut variables, &program) {
+        program
+    }
+
+    #[test]
+    fn jumps_correctly() {
+        let program_src = [
+            "5 let a=3 / 1",
+            "10 goto 30",
+            "20 let a = 2 / 1",
+            "30 print a",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        match run(&mut variables, &program, &mut Continuation::default()) {
             Some(e) => panic!("{}", e),
             None => {
-                if let Some(v) = variables.get(&'a') {
-                    assert_eq!(*v, Number::Integer(3));
+                if let Some(v) = variables.get("a") {
+                    assert_eq!(*v, Value::Number(Number::Integer(3)));
                 }
             }
         }
@@ -422,7 +1112,7 @@ mod test {
 
     #[test]
     fn loops_and_calls_correctly() {
-        let program_src = vec![
+        let program_src = [
             "5 let n = 2 + (3*4)",
             "10 for i = 1 to 3",
             "20 gosub 100",
@@ -432,24 +1122,14 @@ mod test {
             "110 return",
         ];
 
-        let mut program: Vec<(i32, Statement)> = Vec::new();
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
 
-        for src_line in program_src.iter() {
-            let mut reader = SourceReader::new(src_line.to_string());
-            let line_num = reader.get_integer().expect("Error letting line number");
-
-            match reader.build_statement() {
-                Ok(res) => program.push((line_num, res)),
-                Err(e) => panic!("{}", e),
-            }
-        }
-
-        match run(&mut variables, &program) {
+        match run(&mut variables, &program, &mut Continuation::default()) {
             Some(e) => panic!("{}", e),
             None => {
-                if let Some(v) = variables.get(&'n') {
-                    assert_eq!(*v, Number::Integer(11));
+                if let Some(v) = variables.get("n") {
+                    assert_eq!(*v, Value::Number(Number::Integer(11)));
                 }
             }
         }
@@ -458,19 +1138,24 @@ mod test {
     #[test]
     fn loads_source_file() {
         let mut reader = SourceReader::new(String::from("load \"examples/hello.bas\""));
-        let mut variables: HashMap<char, Number> = HashMap::new();
-        let mut program: Vec<(i32, Statement)> = Vec::new();
+        let mut variables = Variables::new();
+        let mut program = Program::default();
 
-        match reader.build_statement() {
+        match reader.build_line() {
             Err(e) => panic!("{}", e),
             Ok(stmt) => {
-                match execute_immediate(&stmt, &mut variables, &mut program) {
+                match execute_immediate(
+                    &stmt,
+                    &mut variables,
+                    &mut program,
+                    &mut Continuation::default(),
+                ) {
                     Some(e) => panic!("{}", e),
                     None => {
-                        assert_eq!(program.len(), 1);
-                        if let Some((n, p_stmt)) = program.get(0) {
+                        assert_eq!(program.lines.len(), 1);
+                        if let Some((n, p_stmt)) = program.lines.first() {
                             assert_eq!(*n, 10);
-                            if let Statement::Print(_) = p_stmt {
+                            if let [Statement::Print(_)] = p_stmt.as_slice() {
                                 // OK
                             } else {
                                 panic!("Wrong statement");
@@ -481,4 +1166,776 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn branches_with_else() {
+        let program_src = [
+            "10 a = 1 : b = 2",
+            "20 if a = 1 and not b = 1 then x = 1 else x = 2",
+            "30 if a = 2 or b = 1 then y = 1 : y = 2 else y = 3 : y = y + 1",
+            "40 if a = 1 then if b = 1 then z = 1 else z = 2 else z = 3",
+            "50 t = (a < b) : f = (a > b)",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        let int = |i| Some(Value::Number(Number::Integer(i)));
+        assert_eq!(variables.get("x").cloned(), int(1));
+        assert_eq!(variables.get("y").cloned(), int(4));
+        assert_eq!(variables.get("z").cloned(), int(2));
+        assert_eq!(variables.get("t").cloned(), int(-1));
+        assert_eq!(variables.get("f").cloned(), int(0));
+    }
+
+    #[test]
+    fn branches_with_block_if() {
+        let program_src = [
+            "5 a = 0 : b = 0 : c = 0 : d = 0",
+            "10 for i = 1 to 4",
+            "20 if i = 1 then",
+            "30 a = a + 1",
+            "40 elseif i = 2 then",
+            "50 if a = 1 then",
+            "60 b = b + 1",
+            "70 else",
+            "80 b = b + 100",
+            "90 end if",
+            "100 elseif i = 3 then",
+            "110 c = c + 1",
+            "120 else",
+            "130 d = d + 1",
+            "140 end if",
+            "150 next",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        for var in ["a", "b", "c", "d"] {
+            assert_eq!(
+                variables.get(var).cloned(),
+                Some(Value::Number(Number::Integer(1)))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unmatched_block_if() {
+        let program = parse_program(&["10 print 1", "20 if 1 then", "30 print 2"]);
+        let mut variables = Variables::new();
+
+        match run(&mut variables, &program, &mut Continuation::default()) {
+            Some(e) if e.kind == ErrorKind::Syntax => {
+                assert_eq!(e.to_string(), "If without end if in line 20")
+            }
+            _ => panic!("Expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn loops_with_while_and_do() {
+        let program_src = [
+            "10 i = 0 : n = 0",
+            "20 while i < 3",
+            "30 j = 0",
+            "40 do : j = j + 1 : n = n + 1 : loop until j = 2",
+            "50 i = i + 1",
+            "60 wend",
+            "70 while i < 0 : n = 100 : wend",
+            "80 do",
+            "90 n = n + 1",
+            "100 loop while n < 10",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(10)))
+        );
+    }
+
+    #[test]
+    fn jumps_out_of_while_and_do() {
+        let program_src = [
+            "10 n = 0 : m = 0",
+            "20 do",
+            "30 while 1",
+            "40 goto 60",
+            "50 wend",
+            "60 n = n + 1",
+            "70 loop until n = 3",
+            "80 do : m = m + 1 : if m < 3 then goto 80",
+            "90 loop until 1",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(3)))
+        );
+        assert_eq!(
+            variables.get("m").cloned(),
+            Some(Value::Number(Number::Integer(3)))
+        );
+    }
+
+    #[test]
+    fn rejects_unmatched_loops() {
+        let sources: [&[&str]; 4] = [
+            &["10 print 1", "20 wend"],
+            &["10 do", "20 wend"],
+            &["10 while 0", "20 print 1"],
+            &["10 while 1", "20 loop until 1"],
+        ];
+        let expected = [
+            "Wend without while in line 20",
+            "Wend without while in line 20",
+            "While without wend in line 10",
+            "Loop without do in line 20",
+        ];
+
+        for (src, message) in sources.iter().zip(expected) {
+            let program = parse_program(src);
+            let mut variables = Variables::new();
+
+            match run(&mut variables, &program, &mut Continuation::default()) {
+                Some(e) if e.kind == ErrorKind::Runtime => assert_eq!(e.to_string(), message),
+                _ => panic!("Expected a runtime error"),
+            }
+        }
+    }
+
+    #[test]
+    fn jumps_to_computed_lines() {
+        let program_src = [
+            "10 n = 2 : x = 0",
+            "20 goto n * 10 + 20",
+            "30 x = 100",
+            "40 on n gosub 100, 200, 300",
+            "50 on 5 goto 100",
+            "60 end",
+            "100 x = x + 1 : return",
+            "200 x = x + 10 : return",
+        ];
+
+        let mut program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            variables.get("x").cloned(),
+            Some(Value::Number(Number::Integer(10)))
+        );
+
+        // A computed target that doesn't exist names both lines.
+        let mut reader = SourceReader::new(String::from("on 2 goto 10, n * 1000"));
+        program[5] = (60, reader.build_line().unwrap());
+
+        match run(&mut variables, &program, &mut Continuation::default()) {
+            Some(e) if e.kind == ErrorKind::Runtime => {
+                assert_eq!(e.to_string(), "Unknown line number 2000 in line 60")
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn calls_functions_defined_later() {
+        let program_src = [
+            "10 x = 10 : y = fnsum(fna(2), x)",
+            "20 def fna(x) = x * x + 1",
+            "30 def fnsum(a, b) = a + b",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            variables.get("y").cloned(),
+            Some(Value::Number(Number::Integer(15)))
+        );
+        assert_eq!(
+            variables.get("x").cloned(),
+            Some(Value::Number(Number::Integer(10)))
+        );
+    }
+
+    #[test]
+    fn calls_procedures() {
+        let program_src = [
+            "10 n = 10 : total = 0",
+            "20 call add(fact(4))",
+            "30 call add(n)",
+            "40 end",
+            "100 function fact(n)",
+            "110 if n <= 1 then fact = 1 else fact = n * fact(n - 1)",
+            "120 end function",
+            "200 sub add(x)",
+            "210 n = 0 : total = -1",
+            "220 end sub",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        // Assignments inside the procedures were all local.
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(10)))
+        );
+        assert_eq!(
+            variables.get("total").cloned(),
+            Some(Value::Number(Number::Integer(0)))
+        );
+        assert_eq!(
+            eval_expression(
+                &Expression::Element(
+                    String::from("fact"),
+                    vec![Expression::Variable(String::from("n"))]
+                ),
+                &mut variables
+            )
+            .unwrap(),
+            Value::Number(Number::Integer(3628800))
+        );
+
+        // Recursion stops at the depth limit.
+        variables.set_max_depth(5);
+        let args = vec![Expression::Numeric(Number::Integer(10))];
+        assert!(call_procedure("fact", &args, &mut variables).is_err());
+    }
+
+    #[test]
+    fn recurses_to_the_depth_limit() {
+        let program_src = [
+            "10 call s(1)",
+            "20 end",
+            "30 sub s(n)",
+            "40 print f(n)",
+            "50 end sub",
+            "60 function f(n)",
+            "70 f = f(n + 1)",
+            "80 end function",
+        ];
+
+        // Run on a thread with the interpreter's stack, as the prompt does.
+        let interpreter = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let program = parse_program(&program_src);
+                let mut variables = Variables::new();
+                variables.set_max_depth(usize::MAX);
+
+                run(&mut variables, &program, &mut Continuation::default())
+            });
+
+        let e = interpreter
+            .expect("Can't start the interpreter")
+            .join()
+            .expect("The interpreter failed")
+            .expect("Expected a runtime error");
+        assert_eq!(
+            (e.kind, e.code, e.line),
+            (ErrorKind::Runtime, ErrorCode::OutOfMemory, Some(70))
+        );
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Procedure calls nested more than {} deep in line 70",
+                MAX_DEPTH_LIMIT
+            )
+        );
+    }
+
+    #[test]
+    fn reads_data() {
+        let program_src = [
+            "10 read a, b$",
+            "20 data 5, \"five\"",
+            "30 restore 50 : read c",
+            "40 restore : read d",
+            "50 data -1.5",
+        ];
+
+        let mut program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            variables.get("a").cloned(),
+            Some(Value::Number(Number::Integer(5)))
+        );
+        assert_eq!(
+            variables.get("b$").cloned(),
+            Some(Value::String(String::from("five")))
+        );
+        assert_eq!(
+            variables.get("c").cloned(),
+            Some(Value::Number(Number::Float(-1.5)))
+        );
+        assert_eq!(
+            variables.get("d").cloned(),
+            Some(Value::Number(Number::Integer(5)))
+        );
+
+        // Reading past the end of the data is an error.
+        let mut reader = SourceReader::new(String::from("read a, b$, c, d"));
+        program[0] = (10, reader.build_line().unwrap());
+
+        match run(&mut variables, &program, &mut Continuation::default()) {
+            Some(e) if e.kind == ErrorKind::Runtime => {
+                assert_eq!(e.to_string(), "Out of data in line 10")
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn switches_integer_modes() {
+        let program_src = [
+            "10 integer big",
+            "20 f = 1 : for i = 1 to 25 : f = f * i : next",
+            "30 integer 64",
+            "40 g = 3000000000 * 2",
+            "50 read h",
+            "60 data 3000000000",
+            "70 integer 32",
+            "80 k = 3000000000",
+        ];
+
+        let mut program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            variables.get("f").map(|v| v.to_string()),
+            Some(String::from("15511210043330985984000000"))
+        );
+        assert!(matches!(
+            variables.get("g"),
+            Some(Value::Number(Number::Integer(6000000000)))
+        ));
+        assert!(matches!(
+            variables.get("h"),
+            Some(Value::Number(Number::Integer(3000000000)))
+        ));
+
+        // Literals too big for 32 bits are floats in the default mode.
+        assert!(matches!(
+            variables.get("k"),
+            Some(Value::Number(Number::Float(_)))
+        ));
+
+        // ...and arithmetic that goes beyond 32 bits overflows.
+        let mut reader = SourceReader::new(String::from("k = 65536 * 65536"));
+        program[7] = (80, reader.build_line().unwrap());
+        assert!(run(&mut variables, &program, &mut Continuation::default()).is_some());
+    }
+
+    #[test]
+    fn nests_for_loops() {
+        let program_src = [
+            "10 n = 0 : r = 0",
+            "20 for i = 1 to 3 : for j = 1 to 2",
+            "30 n = n + 1",
+            "40 next j, i",
+            "50 for i = 1 to 2 : for i = 1 to 3 : n = n + 1 : next : next i",
+            "60 r = r + 1 : for k = 1 to 10",
+            "70 if k = 2 then goto 90",
+            "80 next k",
+            "90 if r < 3 then goto 60",
+            "100 for t = 1 to 2 : for k = 1 to 5 : if k = 3 then goto 120",
+            "110 next k",
+            "120 next t",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        // 6 passes through the nested loops, then 3 through the inner loop
+        // with the same variable before it leaves the outer one too.
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(9)))
+        );
+
+        // Loops that are jumped out of start again from the beginning.
+        for (var, val) in [("r", 3), ("t", 3), ("k", 3)] {
+            assert_eq!(
+                variables.get(var).cloned(),
+                Some(Value::Number(Number::Integer(val)))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_next() {
+        let sources: [&[&str]; 4] = [
+            &["10 print 1", "20 next"],
+            &["10 for i = 1 to 2", "20 next j"],
+            &["10 for i = 1 to 2 : next i", "20 next i"],
+            &[
+                "10 for i = 1 to 3",
+                "20 goto 50",
+                "30 next i",
+                "50 print",
+                "60 next i",
+            ],
+        ];
+        let expected = [
+            "Next without for in line 20",
+            "Wrong next variable in line 20",
+            "Next without for in line 20",
+            "Next without for in line 60",
+        ];
+
+        for (src, message) in sources.iter().zip(expected) {
+            let program = parse_program(src);
+            let mut variables = Variables::new();
+
+            match run(&mut variables, &program, &mut Continuation::default()) {
+                Some(e) if e.kind == ErrorKind::Runtime => assert_eq!(e.to_string(), message),
+                _ => panic!("Expected a runtime error"),
+            }
+        }
+    }
+
+    #[test]
+    fn loops_with_float_steps() {
+        let program_src = [
+            "10 n = 0 : m = 0 : s = 0",
+            "20 for x = 0 to 0.3 step 0.1 : n = n + 1 : next x",
+            "30 for y = 1 to 0.5 step -0.25 : m = m + 1 : next",
+            "40 for z = 0.5 to 3 : s = s + z : next",
+        ];
+
+        let mut program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        // Adding up 0.1 three times goes just past 0.3, but the loop still
+        // runs for it.
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(4)))
+        );
+        assert_eq!(
+            variables.get("m").cloned(),
+            Some(Value::Number(Number::Integer(3)))
+        );
+        assert_eq!(
+            variables.get("s").cloned(),
+            Some(Value::Number(Number::Float(4.5)))
+        );
+
+        // A step of zero is still an error.
+        let mut reader = SourceReader::new(String::from("for x = 0 to 1 step 0.0 : next"));
+        program[1] = (20, reader.build_line().unwrap());
+
+        match run(&mut variables, &program, &mut Continuation::default()) {
+            Some(e) if e.kind == ErrorKind::Runtime => {
+                assert_eq!(e.to_string(), "Step value cannot be zero in line 20")
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn handles_errors() {
+        let program_src = [
+            "10 on error goto 100 : n = 0",
+            "20 x = 1 / 0 : n = n + 1",
+            "30 a = err : b = erl",
+            "40 read d",
+            "50 c = err : end",
+            "100 n = n + 10",
+            "110 if erl = 20 then resume next",
+            "120 resume 50",
+        ];
+
+        let mut program = parse_program(&program_src);
+        let mut variables = Variables::new();
+
+        if let Some(e) = run(&mut variables, &program, &mut Continuation::default()) {
+            panic!("{}", e);
+        }
+
+        for (var, val) in [("n", 21), ("a", 11), ("b", 20), ("c", 4)] {
+            assert_eq!(
+                variables.get(var).cloned(),
+                Some(Value::Number(Number::Integer(val)))
+            );
+        }
+
+        // An error in the handler itself stops the program.
+        let mut reader = SourceReader::new(String::from("n = 1 / 0"));
+        program[5] = (100, reader.build_line().unwrap());
+
+        let e = run(&mut variables, &program, &mut Continuation::default())
+            .expect("Expected a runtime error");
+        assert_eq!(
+            (e.kind, e.code, e.line),
+            (ErrorKind::Runtime, ErrorCode::DivisionByZero, Some(100))
+        );
+
+        // So does any error once the handler is turned off.
+        let mut reader = SourceReader::new(String::from("on error goto 0"));
+        program[0] = (10, reader.build_line().unwrap());
+
+        let e = run(&mut variables, &program, &mut Continuation::default())
+            .expect("Expected a runtime error");
+        assert_eq!(
+            (e.kind, e.code, e.line),
+            (ErrorKind::Runtime, ErrorCode::DivisionByZero, Some(20))
+        );
+
+        // Resuming without an error is an error too.
+        let mut reader = SourceReader::new(String::from("resume next"));
+        program[0] = (10, reader.build_line().unwrap());
+
+        let e = run(&mut variables, &program, &mut Continuation::default())
+            .expect("Expected a runtime error");
+        assert_eq!(
+            (e.kind, e.code, e.line),
+            (ErrorKind::Runtime, ErrorCode::ResumeWithoutError, Some(10))
+        );
+    }
+
+    #[test]
+    fn stops_and_continues() {
+        let program_src = [
+            "10 n = 0 : d = 0",
+            "20 for i = 1 to 3 : n = n + i : stop : next i",
+            "30 x = 6 / d",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+        let mut continuation = Continuation::default();
+
+        assert!(matches!(
+            run(&mut variables, &program, &mut continuation),
+            Some(e) if e.kind == ErrorKind::Break && e.line == Some(20)
+        ));
+        assert!(matches!(
+            cont(&mut variables, &program, &mut continuation),
+            Some(e) if e.kind == ErrorKind::Break && e.line == Some(20)
+        ));
+        assert!(matches!(
+            cont(&mut variables, &program, &mut continuation),
+            Some(e) if e.kind == ErrorKind::Break && e.line == Some(20)
+        ));
+
+        // Variables can be changed before continuing. After an error, the
+        // statement that failed is tried again.
+        assert!(cont(&mut variables, &program, &mut continuation).is_some());
+        variables
+            .set("d", Value::Number(Number::Integer(2)))
+            .unwrap();
+        assert!(cont(&mut variables, &program, &mut continuation).is_none());
+
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(6)))
+        );
+        assert_eq!(
+            variables.get("x").cloned(),
+            Some(Value::Number(Number::Integer(3)))
+        );
+
+        // A finished or changed program can't be continued.
+        let e =
+            cont(&mut variables, &program, &mut continuation).expect("Expected a runtime error");
+        assert_eq!(
+            (e.kind, e.code),
+            (ErrorKind::Runtime, ErrorCode::CantContinue)
+        );
+
+        assert!(run(&mut variables, &program, &mut continuation).is_some());
+        continuation.program_changed();
+
+        match cont(&mut variables, &program, &mut continuation) {
+            Some(e) if e.kind == ErrorKind::Runtime => {
+                assert_eq!(
+                    e.to_string(),
+                    "Can't continue after the program has been changed"
+                )
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn stops_inside_procedures() {
+        let program_src = [
+            "10 n = 0 : call foo(1)",
+            "20 n = n + 100",
+            "100 sub foo(x)",
+            "110 n = n + x : stop : n = n + 10",
+            "120 end sub",
+        ];
+
+        let program = parse_program(&program_src);
+        let mut variables = Variables::new();
+        let mut continuation = Continuation::default();
+
+        // There's no way back into the middle of the call, so the program
+        // can't be continued.
+        let e = run(&mut variables, &program, &mut continuation).expect("Expected a break");
+        assert_eq!(
+            (e.kind, e.code, e.line),
+            (ErrorKind::Break, ErrorCode::CantContinue, Some(110))
+        );
+        assert_eq!(e.to_string(), "Break inside a sub or function in line 110");
+
+        let e =
+            cont(&mut variables, &program, &mut continuation).expect("Expected a runtime error");
+        assert_eq!(
+            (e.kind, e.code),
+            (ErrorKind::Runtime, ErrorCode::CantContinue)
+        );
+        assert_eq!(
+            variables.get("n").cloned(),
+            Some(Value::Number(Number::Integer(0)))
+        );
+    }
+
+    #[test]
+    fn marks_where_errors_happen() {
+        let program_src = [
+            "10 a = 0 : b = 5 /   a",
+            "20 def fna(x) = sqr(x)",
+            "30 print 1 + fna(-1)",
+            "40 print 1 :  goto 99",
+        ];
+
+        let mut program = Program::default();
+        let mut variables = Variables::new();
+
+        // Keep the text of each line as typed, as the prompt does.
+        for src_line in program_src.iter() {
+            let mut reader = SourceReader::new(src_line.to_string());
+            let line_num = reader.get_integer().expect("Error getting line number");
+            let statements = reader.build_line().expect("Error building line");
+            program.update((line_num, statements), src_line, reader.statement_spans());
+        }
+
+        let e = run(&mut variables, &program.lines, &mut Continuation::default())
+            .expect("Expected a runtime error");
+        let e = program.with_source(e);
+        assert_eq!(
+            (e.line, e.source.as_deref()),
+            (Some(10), Some(program_src[0]))
+        );
+        assert_eq!(
+            e.render(),
+            "Runtime error: Division by zero in line 10\n  |\n  | 10 a = 0 : b = 5 /   a\n  |                ^^^^^^^"
+        );
+
+        // An error inside a function marks where it was called.
+        let e = run(
+            &mut variables,
+            &program.lines[1..],
+            &mut Continuation::default(),
+        )
+        .expect("Expected a runtime error");
+        assert_eq!(
+            (e.line, program.with_source(e).span),
+            (
+                Some(30),
+                Some(Span {
+                    column: 14,
+                    width: 7
+                })
+            )
+        );
+
+        // An error in a statement with nothing more precise to mark marks
+        // the whole statement.
+        let e = run(
+            &mut variables,
+            &program.lines[3..],
+            &mut Continuation::default(),
+        )
+        .expect("Expected a runtime error");
+        assert_eq!(
+            program.with_source(e).render(),
+            "Runtime error: Unknown line number 99 in line 40\n  |\n  | 40 print 1 :  goto 99\n  |               ^^^^^^^"
+        );
+
+        // Deleting a line forgets its text too.
+        program.update((10, Vec::new()), "10", &[]);
+        let e = BasicError::runtime(ErrorCode::Overflow, "Overflow").in_line(10);
+        assert_eq!(program.with_source(e).source, None);
+
+        // Syntax errors mark where reading the line stopped.
+        let mut reader = SourceReader::new(String::from("print 1 +* 2"));
+        let Err(e) = reader.build_line() else {
+            panic!("Expected a syntax error");
+        };
+        assert_eq!((e.kind, e.code), (ErrorKind::Syntax, ErrorCode::Syntax));
+        assert_eq!(e.source.as_deref(), Some("print 1 +* 2"));
+        assert!(e.span.is_some());
+
+        // Errors in immediate mode mark the part of the line that failed.
+        let statements = SourceReader::new(String::from("print sqr(-1) + 2"))
+            .build_line()
+            .expect("Error building line");
+        let e = execute_immediate(
+            &statements,
+            &mut variables,
+            &mut program,
+            &mut Continuation::default(),
+        )
+        .expect("Expected a runtime error");
+        assert_eq!(
+            e.in_source("print sqr(-1) + 2").render(),
+            "Runtime error: Invalid argument -1 to function sqr\n  |\n  | print sqr(-1) + 2\n  |       ^^^^^^^"
+        );
+    }
 }
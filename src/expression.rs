@@ -1,109 +1,58 @@
-use std::{
-    collections::HashMap,
-    fmt::Display,
-    ops::{Add, Div, Mul, Sub},
+use std::fmt::Display;
+
+use crate::{
+    errors::{BasicError, ErrorCode, Span},
+    executor::call_procedure,
+    function::{CONSTANTS, eval_function},
+    number::Number,
+    parser::{AND, MOD, NOT, OR},
+    variables::Variables,
 };
 
-use crate::{errors::BasicError, function::eval_function};
+/// The numeric value of a true comparison or logical operation. As in most
+/// BASICs, true is -1 and false is 0. Any non-zero value counts as true
+/// in a condition.
+pub const TRUE: i64 = -1;
 
-/// A numeric value, either an integer or a float.
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
-pub enum Number {
-    Integer(i32),
-    Float(f64),
-}
-
-impl Number {
-    pub fn is_int(&self) -> bool {
-        match self {
-            Self::Integer(_) => true,
-            _ => false,
-        }
-    }
-
-    pub fn int_value(&self) -> Result<i32, BasicError> {
-        match self {
-            Self::Integer(i) => Ok(*i),
-            _ => Err(BasicError::RuntimeError(String::from("Type error"))),
-        }
-    }
-}
+/// The numeric value of a false comparison or logical operation.
+pub const FALSE: i64 = 0;
 
-impl Add for Number {
-    type Output = Self;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        match self {
-            Number::Integer(self_i) => match rhs {
-                Number::Integer(other_i) => Number::Integer(self_i + other_i),
-                Number::Float(other_f) => Number::Float(self_i as f64 + other_f),
-            },
-            Number::Float(self_f) => match rhs {
-                Number::Integer(other_i) => Number::Float(self_f + other_i as f64),
-                Number::Float(other_f) => Number::Float(self_f + other_f),
-            },
-        }
-    }
+/// A value produced by evaluating an expression: either a number or a
+/// string.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(Number),
+    String(String),
 }
 
-impl Sub for Number {
-    type Output = Self;
-
-    fn sub(self, rhs: Self) -> Self::Output {
+impl Value {
+    /// Get the numeric value, or a type mismatch error if this is a string.
+    pub fn number_value(&self) -> Result<Number, BasicError> {
         match self {
-            Number::Integer(self_i) => match rhs {
-                Number::Integer(other_i) => Number::Integer(self_i - other_i),
-                Number::Float(other_f) => Number::Float(self_i as f64 - other_f),
-            },
-            Number::Float(self_f) => match rhs {
-                Number::Integer(other_i) => Number::Float(self_f - other_i as f64),
-                Number::Float(other_f) => Number::Float(self_f - other_f),
-            },
+            Self::Number(n) => Ok(n.clone()),
+            Self::String(_) => Err(type_mismatch()),
         }
     }
 }
 
-impl Mul for Number {
-    type Output = Self;
-
-    fn mul(self, rhs: Self) -> Self::Output {
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Number::Integer(self_i) => match rhs {
-                Number::Integer(other_i) => Number::Integer(self_i * other_i),
-                Number::Float(other_f) => Number::Float(self_i as f64 * other_f),
-            },
-            Number::Float(self_f) => match rhs {
-                Number::Integer(other_i) => Number::Float(self_f * other_i as f64),
-                Number::Float(other_f) => Number::Float(self_f * other_f),
-            },
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
         }
     }
 }
 
-impl Div for Number {
-    type Output = Self;
-
-    fn div(self, rhs: Self) -> Self::Output {
-        match self {
-            Number::Integer(self_i) => match rhs {
-                Number::Integer(other_i) => Number::Integer(self_i / other_i),
-                Number::Float(other_f) => Number::Float(self_i as f64 / other_f),
-            },
-            Number::Float(self_f) => match rhs {
-                Number::Integer(other_i) => Number::Float(self_f / other_i as f64),
-                Number::Float(other_f) => Number::Float(self_f / other_f),
-            },
-        }
-    }
+/// The error raised when a string is used where a number is expected, or
+/// vice versa.
+pub fn type_mismatch() -> BasicError {
+    BasicError::runtime(ErrorCode::TypeMismatch, "Type mismatch")
 }
 
-impl Display for Number {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Number::Integer(n) => write!(f, "{}", n),
-            Number::Float(n) => write!(f, "{}", n),
-        }
-    }
+/// Is the named variable a string variable (i.e. does its name end in `$`)?
+pub fn is_string_var(name: &str) -> bool {
+    name.ends_with('$')
 }
 
 /// An expression in the language.
@@ -111,7 +60,10 @@ impl Display for Number {
 pub enum Expression {
     String(String),
     Numeric(Number),
-    Variable(char),
+    Variable(String),
+
+    /// An element of an array, with an index for each dimension
+    Element(String, Vec<Expression>),
 
     /// An operator is a recursive binary tree where non-leaf nodes
     /// are operators.
@@ -119,20 +71,94 @@ pub enum Expression {
 
     /// A function call with optional arguments
     Function(String, Vec<Expression>),
+
+    /// A comparison of two values, giving true or false
+    Relation(Relop, Box<Expression>, Box<Expression>),
+
+    /// A logical and/or of two values, giving true or false
+    Logical(LogicalOp, Box<Expression>, Box<Expression>),
+
+    /// The logical negation of a value
+    Not(Box<Expression>),
+
+    /// The arithmetic negation of a value, i.e. a unary minus
+    Negate(Box<Expression>),
+
+    /// An expression along with where it is in its line, so that an error
+    /// in it can be marked
+    Spanned(Span, Box<Expression>),
 }
 
-// Does this expression override the usual order of precedence by structuring
-// addops below mulops in the tree?
-fn override_precedence(op: &ArithOp, exp: &Expression) -> bool {
-    match op {
-        ArithOp::Multiply | ArithOp::Divide => match exp {
-            Expression::Operator(other_op, _, _) => match other_op {
-                ArithOp::Add | ArithOp::Subtract => true,
-                _ => false,
-            },
-            _ => false,
-        },
-        _ => false,
+impl Expression {
+    /// Get the expression without any note of where it is in its line, so
+    /// that tests can check how it was parsed.
+    #[cfg(test)]
+    pub fn unspanned(&self) -> &Expression {
+        match self {
+            Expression::Spanned(_, exp) => exp.unspanned(),
+            exp => exp,
+        }
+    }
+}
+
+// The precedence of the expression's top-level operator, used to decide where
+// parentheses are needed when displaying it. Higher numbers bind tighter.
+fn precedence(exp: &Expression) -> u8 {
+    match exp {
+        Expression::Spanned(_, exp) => precedence(exp),
+        Expression::Logical(LogicalOp::Or, _, _) => 1,
+        Expression::Logical(LogicalOp::And, _, _) => 2,
+        Expression::Not(_) => 3,
+        Expression::Relation(_, _, _) => 4,
+        Expression::Operator(ArithOp::Add | ArithOp::Subtract, _, _) => 5,
+        Expression::Operator(ArithOp::Modulus, _, _) => 6,
+        Expression::Operator(ArithOp::IntDivide, _, _) => 7,
+        Expression::Operator(ArithOp::Multiply | ArithOp::Divide, _, _) => 8,
+        Expression::Negate(_) => UNARY_PRECEDENCE,
+        Expression::Numeric(n) if n.float_value() < 0.0 => UNARY_PRECEDENCE,
+        Expression::Operator(ArithOp::Power, _, _) => 10,
+        _ => 11,
+    }
+}
+
+// The precedence of a unary minus, which is also allowed directly after `^`.
+const UNARY_PRECEDENCE: u8 = 9;
+
+// Display a binary operation. A child expression is put in parentheses if it
+// binds more loosely than its parent, or just as loosely on the side the
+// operator doesn't group from: the right for most operators, which group
+// left to right, but the left for `^`. The right-hand side of `^` can also
+// be a unary minus without parentheses, as in `2^-1`.
+fn fmt_binary(
+    f: &mut std::fmt::Formatter<'_>,
+    parent: &Expression,
+    l_exp: &Expression,
+    op: &dyn Display,
+    r_exp: &Expression,
+) -> std::fmt::Result {
+    let is_power = matches!(parent, Expression::Operator(ArithOp::Power, _, _));
+
+    let l_parens = match is_power {
+        true => precedence(l_exp) <= precedence(parent),
+        false => precedence(l_exp) < precedence(parent),
+    };
+    let r_parens = match is_power {
+        true => precedence(r_exp) < UNARY_PRECEDENCE,
+        false => precedence(r_exp) <= precedence(parent),
+    };
+
+    if l_parens {
+        write!(f, "({})", l_exp)?;
+    } else {
+        write!(f, "{}", l_exp)?;
+    }
+
+    write!(f, "{}", op)?;
+
+    if r_parens {
+        write!(f, "({})", r_exp)
+    } else {
+        write!(f, "{}", r_exp)
     }
 }
 
@@ -140,71 +166,90 @@ impl Display for Expression {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Expression::Numeric(n) => write!(f, "{}", n),
-            Expression::Variable(c) => write!(f, "{}", c),
+            Expression::Variable(name) => write!(f, "{}", name),
+            Expression::Element(name, indices) => write!(f, "{}({})", name, join(indices)),
             Expression::String(s) => write!(f, "\"{}\"", s),
-            Expression::Operator(op, l_exp, r_exp) => {
-                // Print left expression, adding parentheses if precedence
-                // is overridden.
-                if override_precedence(op, &l_exp.as_ref().unwrap()) {
-                    let _ = write!(f, "(");
-                }
-
-                match write!(f, "{}", l_exp.as_ref().expect("Error")) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
-                }
-
-                if override_precedence(op, &l_exp.as_ref().unwrap()) {
-                    let _ = write!(f, ")");
+            Expression::Operator(op, l_exp, r_exp) => fmt_binary(
+                f,
+                self,
+                l_exp.as_ref().expect("Error"),
+                op,
+                r_exp.as_ref().expect("Error"),
+            ),
+            Expression::Function(name, _) if CONSTANTS.contains(&name.as_str()) => {
+                write!(f, "{}", name)
+            }
+            Expression::Function(name, args) => write!(f, "{}({})", name, join(args)),
+            Expression::Relation(relop, l_exp, r_exp) => fmt_binary(f, self, l_exp, relop, r_exp),
+            Expression::Logical(op, l_exp, r_exp) => fmt_binary(f, self, l_exp, op, r_exp),
+            Expression::Not(exp) => {
+                if precedence(exp) < precedence(self) {
+                    write!(f, "{} ({})", NOT, exp)
+                } else {
+                    write!(f, "{} {}", NOT, exp)
                 }
-
-                // Print operator
-                match write!(f, "{}", op) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
+            }
+            Expression::Negate(exp) => {
+                if precedence(exp) < precedence(self) {
+                    write!(f, "-({})", exp)
+                } else {
+                    write!(f, "-{}", exp)
                 }
+            }
+            Expression::Spanned(_, exp) => write!(f, "{}", exp),
+        }
+    }
+}
 
-                // Print right expression, adding parentheses if precedence
-                // is overridden.
-                if override_precedence(op, &r_exp.as_ref().unwrap()) {
-                    let _ = write!(f, "(");
-                }
+/// Join a list of expressions into a comma-separated string.
+pub fn join(exps: &[Expression]) -> String {
+    exps.iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
 
-                match write!(f, "{}", r_exp.as_ref().expect("Error")) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
-                }
+/// The target of an assignment: either a simple variable or an element
+/// of an array.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Variable(String),
+    Element(String, Vec<Expression>),
+}
 
-                if override_precedence(op, &r_exp.as_ref().unwrap()) {
-                    let _ = write!(f, ")");
-                }
+impl Target {
+    /// The name of the variable or array being assigned to.
+    pub fn name(&self) -> &str {
+        match self {
+            Target::Variable(name) => name,
+            Target::Element(name, _) => name,
+        }
+    }
 
-                return Ok(());
+    /// Assign a value to the target.
+    pub fn assign(&self, variables: &mut Variables, val: Value) -> Result<(), BasicError> {
+        match self {
+            Target::Variable(name) => variables.set(name, val),
+            Target::Element(name, indices) => {
+                let indices = eval_indices(indices, variables)?;
+                variables.set_element(name, &indices, val)
             }
-            Expression::Function(name, args) => {
-                let mut output = String::new();
-                let mut first = true;
-
-                output.push_str(&format!("{}(", name));
-
-                for a in args.iter() {
-                    if first {
-                        output.push_str(&format!("{}", a));
-                        first = false;
-                    } else {
-                        output.push_str(&format!(", {}", a));
-                    }
-                }
+        }
+    }
+}
 
-                output.push_str(")");
-                write!(f, "{}", output)
-            }
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Variable(name) => write!(f, "{}", name),
+            Target::Element(name, indices) => write!(f, "{}({})", name, join(indices)),
         }
     }
 }
 
 /// Relative operators.
-#[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug)]
 pub enum Relop {
     EQ,
     NEQ,
@@ -217,25 +262,28 @@ pub enum Relop {
 impl Display for Relop {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Relop::EQ => write!(f, "{}", "="),
-            Relop::NEQ => write!(f, "{}", "<>"),
-            Relop::LT => write!(f, "{}", "<"),
-            Relop::LTE => write!(f, "{}", "<="),
-            Relop::GT => write!(f, "{}", ">"),
-            Relop::GTE => write!(f, "{}", ">="),
+            Relop::EQ => write!(f, "="),
+            Relop::NEQ => write!(f, "<>"),
+            Relop::LT => write!(f, "<"),
+            Relop::LTE => write!(f, "<="),
+            Relop::GT => write!(f, ">"),
+            Relop::GTE => write!(f, ">="),
         }
     }
 }
 
-// TODO Merge with Expression type?
-pub enum Condition {
-    Boolean(Expression, Relop, Expression),
+/// Logical operators.
+#[derive(Copy, Clone, Debug)]
+pub enum LogicalOp {
+    And,
+    Or,
 }
 
-impl Display for Condition {
+impl Display for LogicalOp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Condition::Boolean(l_exp, relop, r_exp) => write!(f, "{}{}{}", l_exp, relop, r_exp),
+            LogicalOp::And => write!(f, " {} ", AND),
+            LogicalOp::Or => write!(f, " {} ", OR),
         }
     }
 }
@@ -247,56 +295,275 @@ pub enum ArithOp {
     Subtract,
     Multiply,
     Divide,
+    /// Division rounded towards zero, written `\\`.
+    IntDivide,
+    /// The remainder after integer division.
+    Modulus,
+    Power,
 }
 
 impl Display for ArithOp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ArithOp::Add => write!(f, "{}", '+'),
-            ArithOp::Subtract => write!(f, "{}", '-'),
-            ArithOp::Multiply => write!(f, "{}", '*'),
-            ArithOp::Divide => write!(f, "{}", '/'),
+            ArithOp::Add => write!(f, "+"),
+            ArithOp::Subtract => write!(f, "-"),
+            ArithOp::Multiply => write!(f, "*"),
+            ArithOp::Divide => write!(f, "/"),
+            ArithOp::IntDivide => write!(f, "\\"),
+            ArithOp::Modulus => write!(f, " {} ", MOD),
+            ArithOp::Power => write!(f, "^"),
         }
     }
 }
 
-/// Evaluate an expression and reduce it to a single integer value.
-pub fn eval_expression(
-    root: Expression,
-    variables: &HashMap<char, Number>,
-) -> Result<Number, BasicError> {
+/// Is the name that of a procedure defined with `function`?
+fn is_procedure_function(name: &str, variables: &Variables) -> bool {
+    variables.get_procedure(name).is_some_and(|p| p.is_function)
+}
+
+/// Call a procedure defined with `function` and return its result.
+fn call_function(
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+) -> Result<Value, BasicError> {
+    match call_procedure(name, args, variables)? {
+        Some(val) => Ok(val),
+        None => Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Function {} returned no value", name),
+        )),
+    }
+}
+
+/// Evaluate an expression and reduce it to a single value.
+pub fn eval_expression(root: &Expression, variables: &mut Variables) -> Result<Value, BasicError> {
     match root {
-        Expression::Numeric(n) => return Ok(n),
-        Expression::Variable(c) => match variables.get(&c) {
-            Some(v) => return Ok(v.clone()),
-            _ => {
-                return Err(BasicError::RuntimeError(String::from(format!(
-                    "Unknown variable {}",
-                    c
-                ))));
-            }
+        Expression::String(s) => Ok(Value::String(s.clone())),
+        Expression::Numeric(n) => Ok(Value::Number(n.clone().fitted())),
+        Expression::Variable(name) => match variables.get(name) {
+            Some(v) => Ok(v.clone()),
+
+            // It may be a call to a function without any arguments.
+            None if is_procedure_function(name, variables) => call_function(name, &[], variables),
+
+            _ => Err(BasicError::runtime(
+                ErrorCode::IllegalFunctionCall,
+                format!("Unknown variable {}", name),
+            )),
         },
-        Expression::Operator(op, l_exp, r_exp) => match op {
-            ArithOp::Add => {
-                return Ok(eval_expression(*l_exp.unwrap(), variables)?
-                    + eval_expression(*r_exp.unwrap(), variables)?);
+        // Function calls look just like array elements.
+        Expression::Element(name, args) if is_procedure_function(name, variables) => {
+            call_function(name, args, variables)
+        }
+        Expression::Element(name, indices) => {
+            let indices = eval_indices(indices, variables)?;
+            variables.get_element(name, &indices)
+        }
+        Expression::Operator(op, l_exp, r_exp) => {
+            let l_val = eval_expression(l_exp.as_ref().expect("Error"), variables)?;
+            let r_val = eval_expression(r_exp.as_ref().expect("Error"), variables)?;
+
+            // Strings can only be concatenated.
+            if let (Value::String(l), Value::String(r)) = (&l_val, &r_val) {
+                return match op {
+                    ArithOp::Add => Ok(Value::String(format!("{}{}", l, r))),
+                    _ => Err(type_mismatch()),
+                };
             }
-            ArithOp::Subtract => {
-                return Ok(eval_expression(*l_exp.unwrap(), variables)?
-                    - eval_expression(*r_exp.unwrap(), variables)?);
+
+            let l_num = l_val.number_value()?;
+            let r_num = r_val.number_value()?;
+
+            match op {
+                ArithOp::Add => Ok(Value::Number((l_num + r_num)?)),
+                ArithOp::Subtract => Ok(Value::Number((l_num - r_num)?)),
+                ArithOp::Multiply => Ok(Value::Number((l_num * r_num)?)),
+                ArithOp::Divide => Ok(Value::Number((l_num / r_num)?)),
+                ArithOp::IntDivide => Ok(Value::Number(l_num.int_divide(r_num)?)),
+                ArithOp::Modulus => Ok(Value::Number(l_num.modulus(r_num)?)),
+                ArithOp::Power => Ok(Value::Number(l_num.power(r_num)?)),
             }
-            ArithOp::Multiply => {
-                return Ok(eval_expression(*l_exp.unwrap(), variables)?
-                    * eval_expression(*r_exp.unwrap(), variables)?);
+        }
+        Expression::Negate(exp) => {
+            let num = eval_expression(exp, variables)?.number_value()?;
+            Ok(Value::Number(num.negate()?))
+        }
+        Expression::Function(name, args) => eval_function(name, args, variables),
+
+        // Numbers are compared with numbers and strings with strings; mixing
+        // the two is a type mismatch.
+        Expression::Relation(relop, l_exp, r_exp) => {
+            let l_val = eval_expression(l_exp, variables)?;
+            let r_val = eval_expression(r_exp, variables)?;
+
+            let result = match (l_val, r_val) {
+                (Value::Number(l), Value::Number(r)) => compare(&l, *relop, &r),
+                (Value::String(l), Value::String(r)) => compare(&l, *relop, &r),
+                _ => return Err(type_mismatch()),
+            };
+
+            Ok(truth_value(result))
+        }
+        Expression::Logical(op, l_exp, r_exp) => {
+            let l_val = eval_condition(l_exp, variables)?;
+            let r_val = eval_condition(r_exp, variables)?;
+
+            match op {
+                LogicalOp::And => Ok(truth_value(l_val && r_val)),
+                LogicalOp::Or => Ok(truth_value(l_val || r_val)),
             }
-            ArithOp::Divide => {
-                return Ok(eval_expression(*l_exp.unwrap(), variables)?
-                    / eval_expression(*r_exp.unwrap(), variables)?);
+        }
+        Expression::Not(exp) => Ok(truth_value(!eval_condition(exp, variables)?)),
+
+        // Mark where an error happened, unless a part of this expression
+        // has already been marked.
+        Expression::Spanned(span, exp) => eval_expression(exp, variables).map_err(|e| e.at(*span)),
+    }
+}
+
+/// Evaluate an expression as a condition. Any non-zero number is true;
+/// strings can't be used as conditions.
+pub fn eval_condition(exp: &Expression, variables: &mut Variables) -> Result<bool, BasicError> {
+    let val = eval_expression(exp, variables)?.number_value()?;
+
+    Ok(val != Number::Integer(0) && val != Number::Float(0.0))
+}
+
+/// Convert a boolean to its numeric value.
+fn truth_value(b: bool) -> Value {
+    match b {
+        true => Value::Number(Number::Integer(TRUE)),
+        false => Value::Number(Number::Integer(FALSE)),
+    }
+}
+
+fn compare<T: PartialOrd>(l_val: &T, relop: Relop, r_val: &T) -> bool {
+    match relop {
+        Relop::EQ => l_val == r_val,
+        Relop::NEQ => l_val != r_val,
+        Relop::LT => l_val < r_val,
+        Relop::LTE => l_val <= r_val,
+        Relop::GT => l_val > r_val,
+        Relop::GTE => l_val >= r_val,
+    }
+}
+
+/// Evaluate a list of array subscripts. Any fractional part of a subscript
+/// is discarded.
+pub fn eval_indices(
+    indices: &[Expression],
+    variables: &mut Variables,
+) -> Result<Vec<i32>, BasicError> {
+    let mut values = Vec::new();
+
+    for idx in indices.iter() {
+        values.push(
+            eval_expression(idx, variables)?
+                .number_value()?
+                .small_int_value()?,
+        );
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::SourceReader;
+
+    fn eval(src: &str) -> Result<Value, BasicError> {
+        let statements = SourceReader::new(format!("x = {}", src)).build_line()?;
+        let mut variables = Variables::new();
+
+        for statement in statements.iter() {
+            statement.execute(&mut variables)?;
+        }
+
+        Ok(variables.get("x").cloned().expect("x should be set"))
+    }
+
+    #[test]
+    fn evaluates_operators() {
+        let cases = [
+            ("2^3^2", Number::Integer(512)),
+            ("-2^2", Number::Integer(-4)),
+            ("(-2)^2", Number::Integer(4)),
+            ("2^-1", Number::Float(0.5)),
+            ("2*3^2", Number::Integer(18)),
+            ("7 mod 3", Number::Integer(1)),
+            ("-7 mod 3", Number::Integer(-1)),
+            ("7.6 mod 3", Number::Integer(2)),
+            ("7\\2", Number::Integer(3)),
+            ("10\\4*2", Number::Integer(1)),
+            ("10 mod 4\\3", Number::Integer(0)),
+            ("1 + 10 mod 4", Number::Integer(3)),
+            ("-(1+2)*-3", Number::Integer(9)),
+            ("+4 - -2", Number::Integer(6)),
+        ];
+
+        for (src, expected) in cases {
+            match eval(src) {
+                Ok(val) => assert_eq!(val, Value::Number(expected), "{}", src),
+                Err(e) => panic!("{}: {}", src, e),
             }
-        },
-        Expression::Function(name, args) => {
-            return eval_function(&name, &args, variables);
         }
-        _ => panic!("Invalid type in expression"),
+    }
+
+    #[test]
+    fn rejects_overflow_and_division_by_zero() {
+        let sources = [
+            "2147483647 + 1",
+            "-2147483647 - 2",
+            "65536 * 65536",
+            "-(-2147483647 - 1)",
+            "1 / 0",
+            "1.5 / 0",
+            "(-2147483647 - 1) / -1",
+            "(-2147483647 - 1) \\ -1",
+            "10^308 * 10",
+            "2^1024",
+            "int(10^10)",
+            "fix(-10^10)",
+            "round(10^10)",
+            "abs(-2147483647 - 1)",
+        ];
+
+        for src in sources {
+            assert!(eval(src).is_err(), "{}", src);
+        }
+
+        assert_eq!(
+            eval("2147483647 + 0").unwrap(),
+            Value::Number(Number::Integer(i32::MAX as i64))
+        );
+        assert_eq!(
+            eval("2147483647 + 1.0").unwrap(),
+            Value::Number(Number::Float(2147483648.0))
+        );
+    }
+
+    #[test]
+    fn fits_integer_powers_to_the_mode() {
+        // Powers too big for the mode become floats, like literals do.
+        assert_eq!(
+            eval("2^31").unwrap(),
+            Value::Number(Number::Float(2147483648.0))
+        );
+        assert_eq!(eval("10^10").unwrap(), eval("10000000000").unwrap());
+        assert_eq!(
+            eval("(-2)^31").unwrap(),
+            Value::Number(Number::Integer(i32::MIN as i64))
+        );
+        assert!(eval("10^400").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        assert!(eval("1 mod 0").is_err());
+        assert!(eval("1 \\ 0.2").is_err());
+        assert!(eval("(-8)^0.5").is_err());
+        assert!(eval("-\"a\"").is_err());
     }
 }
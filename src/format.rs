@@ -0,0 +1,404 @@
+use crate::{
+    errors::{BasicError, ErrorCode},
+    expression::{Value, type_mismatch},
+    number::Number,
+};
+
+/// A part of a format string, as used by `print using`.
+#[derive(Debug, PartialEq)]
+enum Field {
+    /// Text printed as it is.
+    Literal(String),
+
+    /// A number, e.g. `+#,###.##`.
+    Number {
+        /// Positions before the decimal point, including any commas.
+        width: usize,
+        /// Digits after the decimal point, if there is one.
+        decimals: Option<usize>,
+        /// Separate thousands with commas?
+        commas: bool,
+        sign: Sign,
+    },
+
+    /// The first character of a string (`!`).
+    FirstChar,
+
+    /// A whole string (`&`).
+    AllChars,
+
+    /// A string padded or cut to a fixed width (`\  \`).
+    FixedWidth(usize),
+}
+
+/// Where the sign of a number goes in a number field.
+#[derive(Debug, PartialEq)]
+enum Sign {
+    /// Only negative numbers have a sign, taking up a digit position.
+    Default,
+    /// A `+` at the start: the sign always comes first.
+    Leading,
+    /// A `+` at the end: the sign always comes last.
+    Trailing,
+    /// A `-` at the end: negative numbers end with a minus.
+    TrailingMinus,
+}
+
+/// Format values according to a format string like those used by
+/// `print using`. Each `#` in a number field is a digit, with an optional
+/// decimal point, commas to separate thousands, and a `+` or `-` for the
+/// sign. `!`, `&` and `\  \` are string fields, `_` prints the following
+/// character literally, and anything else is printed as it is. The format
+/// is reused from the start if there are more values than fields.
+///
+/// # Arguments
+/// * `format` - The format string
+/// * `values` - The values to format
+pub fn format_using(format: &str, values: &[Value]) -> Result<String, BasicError> {
+    let fields = parse_format(format);
+
+    if !fields.iter().any(|f| !matches!(f, Field::Literal(_))) {
+        return Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            "No fields in format string",
+        ));
+    }
+
+    let mut output = String::new();
+    let mut remaining = values.iter().peekable();
+
+    // Go through the format as many times as it takes to use every value,
+    // stopping at the first field without a value.
+    'outer: while remaining.peek().is_some() {
+        for field in fields.iter() {
+            if let Field::Literal(text) = field {
+                output.push_str(text);
+                continue;
+            }
+
+            let Some(val) = remaining.next() else {
+                break 'outer;
+            };
+
+            output.push_str(&format_field(field, val)?);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Split a format string into fields and literal text.
+fn parse_format(format: &str) -> Vec<Field> {
+    let chars: Vec<char> = format.chars().collect();
+    let mut fields: Vec<Field> = Vec::new();
+    let mut literal = String::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let start = idx;
+
+        let field = match chars[idx] {
+            '_' => {
+                // The next character is literal.
+                if let Some(c) = chars.get(idx + 1) {
+                    literal.push(*c);
+                }
+                idx += 2;
+                continue;
+            }
+            '!' => {
+                idx += 1;
+                Some(Field::FirstChar)
+            }
+            '&' => {
+                idx += 1;
+                Some(Field::AllChars)
+            }
+            '\\' => match chars[idx + 1..].iter().position(|c| *c != ' ') {
+                Some(n) if chars[idx + 1 + n] == '\\' => {
+                    idx += n + 2;
+                    Some(Field::FixedWidth(n + 2))
+                }
+                _ => None,
+            },
+            _ => parse_number_field(&chars, &mut idx),
+        };
+
+        match field {
+            Some(f) => {
+                if !literal.is_empty() {
+                    fields.push(Field::Literal(literal.clone()));
+                    literal.clear();
+                }
+                fields.push(f);
+            }
+            None => {
+                literal.push(chars[start]);
+                idx = start + 1;
+            }
+        }
+    }
+
+    if !literal.is_empty() {
+        fields.push(Field::Literal(literal));
+    }
+
+    fields
+}
+
+/// Try to read a number field starting at the given index, moving the
+/// index past it.
+fn parse_number_field(chars: &[char], idx: &mut usize) -> Option<Field> {
+    let is_digit_at = |i: usize| chars.get(i) == Some(&'#');
+    let mut pos = *idx;
+
+    let leading_plus = chars[pos] == '+';
+    if leading_plus {
+        pos += 1;
+    }
+
+    // A field needs at least one digit, either before or after the point.
+    let starts_field = is_digit_at(pos) || (chars.get(pos) == Some(&'.') && is_digit_at(pos + 1));
+    if !starts_field {
+        return None;
+    }
+
+    let mut width = 0;
+    let mut commas = false;
+
+    // Commas only count as part of the field when more digits follow.
+    while is_digit_at(pos) || (chars.get(pos) == Some(&',') && is_digit_at(pos + 1)) {
+        commas |= chars[pos] == ',';
+        width += 1;
+        pos += 1;
+    }
+
+    let decimals = match chars.get(pos) == Some(&'.') {
+        true => {
+            pos += 1;
+            let mut count = 0;
+            while is_digit_at(pos) {
+                count += 1;
+                pos += 1;
+            }
+            Some(count)
+        }
+        false => None,
+    };
+
+    let sign = match (leading_plus, chars.get(pos)) {
+        (true, _) => Sign::Leading,
+        (false, Some('+')) => {
+            pos += 1;
+            Sign::Trailing
+        }
+        (false, Some('-')) => {
+            pos += 1;
+            Sign::TrailingMinus
+        }
+        _ => Sign::Default,
+    };
+
+    *idx = pos;
+
+    Some(Field::Number {
+        width,
+        decimals,
+        commas,
+        sign,
+    })
+}
+
+/// Format a single value for a field.
+fn format_field(field: &Field, val: &Value) -> Result<String, BasicError> {
+    match (field, val) {
+        (
+            Field::Number {
+                width,
+                decimals,
+                commas,
+                sign,
+            },
+            Value::Number(n),
+        ) => Ok(format_number(n, *width, *decimals, *commas, sign)),
+
+        (Field::FirstChar, Value::String(s)) => Ok(s.chars().take(1).collect()),
+
+        (Field::AllChars, Value::String(s)) => Ok(s.clone()),
+
+        (Field::FixedWidth(w), Value::String(s)) => Ok(format!(
+            "{:<w$}",
+            s.chars().take(*w).collect::<String>(),
+            w = *w
+        )),
+
+        _ => Err(type_mismatch()),
+    }
+}
+
+/// Round a value to the given number of decimal places, with halves
+/// rounded away from zero as in Microsoft BASIC, where formatting alone
+/// would round them to even.
+fn round_half_away(value: f64, decimals: usize) -> f64 {
+    let scale = 10f64.powi(decimals as i32);
+    let rounded = (value * scale).round() / scale;
+
+    // Values too large to scale have no fraction to round.
+    match rounded.is_finite() {
+        true => rounded,
+        false => value,
+    }
+}
+
+/// Format a number for a number field. A number too wide for the field is
+/// printed in full, starting with `%`.
+fn format_number(
+    n: &Number,
+    width: usize,
+    decimals: Option<usize>,
+    commas: bool,
+    sign: &Sign,
+) -> String {
+    let value = n.float_value();
+
+    let digits = format!(
+        "{:.*}",
+        decimals.unwrap_or(0),
+        round_half_away(value.abs(), decimals.unwrap_or(0))
+    );
+    let (int_part, frac_part) = match digits.split_once('.') {
+        Some((i, f)) => (i.to_string(), Some(f.to_string())),
+        None => (digits.clone(), None),
+    };
+
+    // A rounded-off value of zero has no sign.
+    let negative = value < 0.0 && digits.chars().any(|c| c.is_ascii_digit() && c != '0');
+
+    let mut int_part = match commas {
+        true => group_thousands(&int_part),
+        false => int_part,
+    };
+
+    // A field with no digits before the point drops a leading zero.
+    if width == 0 && int_part == "0" {
+        int_part.clear();
+    }
+
+    let mut text = match frac_part {
+        Some(f) => format!("{}.{}", int_part, f),
+        None => int_part,
+    };
+
+    let sign_char = match negative {
+        true => '-',
+        false => '+',
+    };
+
+    let mut field_width = width + decimals.map_or(0, |d| d + 1);
+
+    match sign {
+        Sign::Default if negative => text.insert(0, '-'),
+        Sign::Leading => {
+            text.insert(0, sign_char);
+            field_width += 1;
+        }
+        _ => (),
+    }
+
+    let mut output = match text.chars().count() > field_width {
+        true => format!("%{}", text),
+        false => format!("{:>w$}", text, w = field_width),
+    };
+
+    match sign {
+        Sign::Trailing => output.push(sign_char),
+        Sign::TrailingMinus if negative => output.push('-'),
+        Sign::TrailingMinus => output.push(' '),
+        _ => (),
+    }
+
+    output
+}
+
+/// Put commas between each group of three digits, e.g. `1234567` becomes
+/// `1,234,567`.
+fn group_thousands(digits: &str) -> String {
+    let mut output = String::new();
+
+    for (idx, c) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            output.push(',');
+        }
+        output.push(c);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(f: f64) -> Value {
+        Value::Number(Number::Float(f))
+    }
+
+    fn text(s: &str) -> Value {
+        Value::String(String::from(s))
+    }
+
+    #[test]
+    fn formats_numbers() {
+        let cases = [
+            ("###.##", 1.23456, "  1.23"),
+            ("###.##", 0.1 + 0.2, "  0.30"),
+            ("###.##", -12.5, "-12.50"),
+            ("##", 123.0, "%123"),
+            ("#,###,###", 1234567.0, "1,234,567"),
+            ("+##.#", 5.0, " +5.0"),
+            ("+##.#", -5.0, " -5.0"),
+            ("##.#+", 5.0, " 5.0+"),
+            ("##.#-", -5.0, " 5.0-"),
+            ("##.#-", 5.0, " 5.0 "),
+            (".##", 0.5, ".50"),
+            ("###", -0.1, "  0"),
+            ("##.#", 2.25, " 2.3"),
+            ("##.#", -2.25, "-2.3"),
+            ("##", 0.5, " 1"),
+            (".##", 0.125, ".13"),
+        ];
+
+        for (format, val, expected) in cases {
+            assert_eq!(format_using(format, &[num(val)]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn formats_strings_and_literals() {
+        assert_eq!(
+            format_using("Total: $###.## (&)", &[num(9.5), text("paid")]).unwrap(),
+            "Total: $  9.50 (paid)"
+        );
+        assert_eq!(
+            format_using("!\\  \\|", &[text("Hello"), text("world")]).unwrap(),
+            "Hworl|"
+        );
+        assert_eq!(format_using("_##", &[num(1.0)]).unwrap(), "#1");
+    }
+
+    #[test]
+    fn reuses_format_for_extra_values() {
+        assert_eq!(
+            format_using("[##]", &[num(1.0), num(2.0), num(3.0)]).unwrap(),
+            "[ 1][ 2][ 3]"
+        );
+        assert_eq!(format_using("## and ##!", &[num(1.0)]).unwrap(), " 1 and ");
+    }
+
+    #[test]
+    fn rejects_bad_formats() {
+        assert!(format_using("no fields", &[num(1.0)]).is_err());
+        assert!(format_using("##", &[text("x")]).is_err());
+        assert!(format_using("&", &[num(1.0)]).is_err());
+    }
+}
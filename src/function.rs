@@ -1,12 +1,112 @@
 use std::collections::HashMap;
 
+use rand::Rng;
+
 use crate::{
-    errors::BasicError,
-    expression::{Expression, Number, eval_expression},
+    errors::{BasicError, ErrorCode},
+    expression::{Expression, Value, eval_expression, is_string_var, type_mismatch},
+    format::format_using,
+    number::{Number, float_to_int},
+    variables::Variables,
 };
 
 pub const INT: &str = "int";
 pub const RND: &str = "rnd";
+pub const FORMAT: &str = "format$";
+pub const ABS: &str = "abs";
+pub const SGN: &str = "sgn";
+pub const SQR: &str = "sqr";
+pub const SIN: &str = "sin";
+pub const COS: &str = "cos";
+pub const TAN: &str = "tan";
+pub const ATN: &str = "atn";
+pub const LOG: &str = "log";
+pub const EXP: &str = "exp";
+pub const FIX: &str = "fix";
+pub const ROUND: &str = "round";
+pub const MIN: &str = "min";
+pub const MAX: &str = "max";
+pub const PI: &str = "pi";
+pub const ERR: &str = "err";
+pub const ERL: &str = "erl";
+
+/// The names of all built-in functions.
+const FUNCTIONS: [&str; 19] = [
+    INT, RND, FORMAT, ABS, SGN, SQR, SIN, COS, TAN, ATN, LOG, EXP, FIX, ROUND, MIN, MAX, PI, ERR,
+    ERL,
+];
+
+/// Built-in functions that take no arguments, so are shown without
+/// brackets.
+pub const CONSTANTS: [&str; 3] = [PI, ERR, ERL];
+
+/// User-defined function names all start with this prefix.
+pub const FN: &str = "fn";
+
+/// Is the given identifier the name of a built-in or user-defined function?
+pub fn is_function(name: &str) -> bool {
+    FUNCTIONS.contains(&name) || is_user_function(name)
+}
+
+/// Is the given identifier the name of a user-defined function (e.g. `fna`
+/// or `fnsquare$`)?
+pub fn is_user_function(name: &str) -> bool {
+    name.strip_prefix(FN)
+        .is_some_and(|rest| !rest.trim_end_matches('$').is_empty())
+}
+
+/// A function defined with `def`, e.g. `def fna(x, y) = x * y + 1`.
+#[derive(Clone, Debug)]
+pub struct UserFunction {
+    pub params: Vec<String>,
+    pub body: Expression,
+}
+
+/// Define (or redefine) a user function. A function can't call itself,
+/// either directly or through other functions.
+///
+/// # Arguments
+/// * `name` - Name of the function
+/// * `params` - Names of the function's parameters
+/// * `body` - The expression that gives the function's result
+/// * `variables` - The variable table, which holds the function definitions
+///
+pub fn define_function(
+    name: &str,
+    params: &[String],
+    body: &Expression,
+    variables: &mut Variables,
+) -> Result<(), BasicError> {
+    // Follow every call made by the body, and by the functions it calls.
+    let mut to_check: Vec<String> = Vec::new();
+    let mut checked: Vec<String> = Vec::new();
+    collect_calls(body, &mut to_check);
+
+    while let Some(callee) = to_check.pop() {
+        if callee == name {
+            return Err(BasicError::runtime(
+                ErrorCode::IllegalFunctionCall,
+                format!("Function {} can't call itself", name),
+            ));
+        }
+
+        if let Some(f) = variables.get_function(&callee) {
+            collect_calls(&f.body, &mut to_check);
+        }
+        checked.push(callee);
+        to_check.retain(|c| !checked.contains(c));
+    }
+
+    variables.define_function(
+        name,
+        UserFunction {
+            params: params.to_vec(),
+            body: body.clone(),
+        },
+    );
+
+    Ok(())
+}
 
 /// Evaluate a function and return the result.
 ///
@@ -16,55 +116,384 @@ pub const RND: &str = "rnd";
 /// * `variables` - The variable table
 ///
 pub fn eval_function(
-    name: &String,
-    args: &Vec<Expression>,
-    variables: &HashMap<char, Number>,
-) -> Result<Number, BasicError> {
-    match name.as_str().trim() {
-        RND => Ok(rnd()),
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+) -> Result<Value, BasicError> {
+    match name.trim() {
+        RND => rnd(args, variables),
 
         INT => int(args, variables),
 
-        _ => Err(BasicError::RuntimeError(String::from(format!(
-            "Unknown identifier {}",
-            name
-        )))),
+        FIX => fix(args, variables),
+
+        ABS => Ok(Value::Number(number_arg(ABS, args, variables)?.abs()?)),
+
+        SGN => {
+            let sign = match number_arg(SGN, args, variables)?.float_value() {
+                f if f > 0.0 => 1,
+                f if f < 0.0 => -1,
+                _ => 0,
+            };
+            Ok(Value::Number(Number::Integer(sign)))
+        }
+
+        SQR => float_function(SQR, args, variables, |f| f >= 0.0, f64::sqrt),
+
+        SIN => float_function(SIN, args, variables, |_| true, f64::sin),
+
+        COS => float_function(COS, args, variables, |_| true, f64::cos),
+
+        TAN => float_function(TAN, args, variables, |_| true, f64::tan),
+
+        ATN => float_function(ATN, args, variables, |_| true, f64::atan),
+
+        LOG => float_function(LOG, args, variables, |f| f > 0.0, f64::ln),
+
+        EXP => float_function(EXP, args, variables, |_| true, f64::exp),
+
+        ROUND => round(args, variables),
+
+        MIN => min_max(MIN, args, variables),
+
+        MAX => min_max(MAX, args, variables),
+
+        PI => {
+            check_arg_count(PI, args, 0, 0)?;
+            Ok(Value::Number(Number::Float(std::f64::consts::PI)))
+        }
+
+        FORMAT => format(args, variables),
+
+        // The code and line number of the error being handled.
+        ERR => {
+            check_arg_count(ERR, args, 0, 0)?;
+            let code = variables.last_error().0;
+            Ok(Value::Number(Number::Integer(code as i64)))
+        }
+
+        ERL => {
+            check_arg_count(ERL, args, 0, 0)?;
+            let line_num = variables.last_error().1;
+            Ok(Value::Number(Number::Integer(line_num as i64)))
+        }
+
+        _ if is_user_function(name) => call_user_function(name, args, variables),
+
+        _ => Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Unknown identifier {}", name),
+        )),
+    }
+}
+
+/// Check that a function has been given between `min` and `max`
+/// arguments.
+///
+/// # Arguments
+/// * `name` - Name of the function, for the error message
+/// * `args` - Arguments to the function
+/// * `min` - The fewest arguments allowed
+/// * `max` - The most arguments allowed
+///
+fn check_arg_count(
+    name: &str,
+    args: &[Expression],
+    min: usize,
+    max: usize,
+) -> Result<(), BasicError> {
+    if args.len() < min || args.len() > max {
+        return Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Incorrect number of arguments to function {}", name),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Evaluate the single numeric argument of a function.
+fn number_arg(
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+) -> Result<Number, BasicError> {
+    check_arg_count(name, args, 1, 1)?;
+    eval_expression(&args[0], variables)?.number_value()
+}
+
+/// Apply a floating point function to a single numeric argument. An
+/// argument outside the function's domain, or a result too large to
+/// represent, is an error rather than NaN or infinity.
+///
+/// # Arguments
+/// * `name` - Name of the function
+/// * `args` - Arguments to the function
+/// * `variables` - The variable table
+/// * `in_domain` - Whether the function is defined for an argument
+/// * `f` - The function itself
+///
+fn float_function(
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+    in_domain: fn(f64) -> bool,
+    f: fn(f64) -> f64,
+) -> Result<Value, BasicError> {
+    let arg = number_arg(name, args, variables)?.float_value();
+
+    if !in_domain(arg) {
+        return Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Invalid argument {} to function {}", arg, name),
+        ));
+    }
+
+    let result = f(arg);
+    if !result.is_finite() {
+        return Err(BasicError::runtime(
+            ErrorCode::Overflow,
+            format!("Overflow in function {}", name),
+        ));
+    }
+
+    Ok(Value::Number(Number::Float(result)))
+}
+
+/// Round a value down to the nearest integer, e.g. `int(-2.5)` is -3.
+fn int(args: &[Expression], variables: &mut Variables) -> Result<Value, BasicError> {
+    match number_arg(INT, args, variables)? {
+        Number::Float(f) => Ok(Value::Number(Number::from_float(f.floor())?)),
+        n => Ok(Value::Number(n)),
+    }
+}
+
+/// Remove any fractional part of a value and return the integer part,
+/// e.g. `fix(-2.5)` is -2.
+fn fix(args: &[Expression], variables: &mut Variables) -> Result<Value, BasicError> {
+    match number_arg(FIX, args, variables)? {
+        Number::Float(f) => Ok(Value::Number(Number::from_float(f)?)),
+        n => Ok(Value::Number(n)),
+    }
+}
+
+/// Round a value to the nearest integer, or to a number of decimal places
+/// if given, e.g. `round(2.345, 2)`. Halves are rounded away from zero.
+fn round(args: &[Expression], variables: &mut Variables) -> Result<Value, BasicError> {
+    check_arg_count(ROUND, args, 1, 2)?;
+
+    let val = eval_expression(&args[0], variables)?.number_value()?;
+    let places = match args.get(1) {
+        Some(arg) => float_to_int(
+            eval_expression(arg, variables)?
+                .number_value()?
+                .float_value(),
+        )?,
+        None => 0,
+    };
+
+    match (&val, places) {
+        (Number::Integer(_) | Number::Big(_), _) => Ok(Value::Number(val)),
+        (Number::Float(f), 0) => Ok(Value::Number(Number::from_float(f.round())?)),
+        (Number::Float(f), _) => {
+            // Rounding to very many places leaves the value as it is, and
+            // rounding to a very large power of ten leaves nothing.
+            let scale = 10f64.powi(places);
+            let rounded = match (f * scale).round() / scale {
+                r if r.is_finite() => r,
+                _ if places > 0 => *f,
+                _ => 0.0,
+            };
+            Ok(Value::Number(Number::Float(rounded)))
+        }
+    }
+}
+
+/// Find the smallest or largest of two or more values.
+fn min_max(
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+) -> Result<Value, BasicError> {
+    check_arg_count(name, args, 2, usize::MAX)?;
+
+    let mut result = eval_expression(&args[0], variables)?.number_value()?;
+
+    for arg in args[1..].iter() {
+        let val = eval_expression(arg, variables)?.number_value()?;
+
+        let replace = match name {
+            MIN => val.float_value() < result.float_value(),
+            _ => val.float_value() > result.float_value(),
+        };
+        if replace {
+            result = val;
+        }
     }
+
+    Ok(Value::Number(result))
 }
 
-/// Remove any fractional part of a value and returns the integer part.
-fn int(args: &Vec<Expression>, variables: &HashMap<char, Number>) -> Result<Number, BasicError> {
-    if args.len() != 1 {
-        return Err(BasicError::RuntimeError(String::from(
-            "Incorrect number of arguments to function int",
-        )));
+/// Format values as `print using` would, e.g. `format$("##.#", x)`.
+fn format(args: &[Expression], variables: &mut Variables) -> Result<Value, BasicError> {
+    check_arg_count(FORMAT, args, 2, usize::MAX)?;
+
+    let Value::String(format) = eval_expression(&args[0], variables)? else {
+        return Err(type_mismatch());
+    };
+
+    let mut values: Vec<Value> = Vec::new();
+    for arg in args[1..].iter() {
+        values.push(eval_expression(arg, variables)?);
+    }
+
+    Ok(Value::String(format_using(&format, &values)?))
+}
+
+/// Call a user-defined function. The arguments are bound to the
+/// parameters by substituting their values into the function's body, so
+/// the parameters never touch variables of the same name.
+fn call_user_function(
+    name: &str,
+    args: &[Expression],
+    variables: &mut Variables,
+) -> Result<Value, BasicError> {
+    let function = match variables.get_function(name) {
+        Some(f) => f.clone(),
+        None => {
+            return Err(BasicError::runtime(
+                ErrorCode::UndefinedFunction,
+                format!("Undefined function {}", name),
+            ));
+        }
+    };
+
+    if args.len() != function.params.len() {
+        return Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Incorrect number of arguments to function {}", name),
+        ));
     }
 
-    let arg = args.iter().nth(0).expect("Error evaluating function int");
-    let val = eval_expression(arg.clone(), &variables)?;
+    // Evaluate the arguments, which must match their parameters' types.
+    let mut bindings: HashMap<&str, Value> = HashMap::new();
+
+    for (param, arg) in function.params.iter().zip(args.iter()) {
+        let val = eval_expression(arg, variables)?;
+        if matches!(val, Value::String(_)) != is_string_var(param) {
+            return Err(type_mismatch());
+        }
+        bindings.insert(param, val);
+    }
+
+    let result = eval_expression(&bind_params(&function.body, &bindings), variables)?;
+
+    match matches!(result, Value::String(_)) == is_string_var(name) {
+        true => Ok(result),
+        false => Err(type_mismatch()),
+    }
+}
+
+/// Make a copy of an expression with each parameter replaced by its value.
+fn bind_params(exp: &Expression, bindings: &HashMap<&str, Value>) -> Expression {
+    let bind_all = |exps: &[Expression]| -> Vec<Expression> {
+        exps.iter().map(|e| bind_params(e, bindings)).collect()
+    };
+    let bind_box = |e: &Expression| Box::new(bind_params(e, bindings));
+
+    match exp {
+        Expression::Variable(name) => match bindings.get(name.as_str()) {
+            Some(Value::Number(n)) => Expression::Numeric(n.clone()),
+            Some(Value::String(s)) => Expression::String(s.clone()),
+            None => exp.clone(),
+        },
+        Expression::Element(name, indices) => Expression::Element(name.clone(), bind_all(indices)),
+        Expression::Operator(op, l_exp, r_exp) => Expression::Operator(
+            *op,
+            l_exp.as_deref().map(bind_box),
+            r_exp.as_deref().map(bind_box),
+        ),
+        Expression::Function(name, args) => Expression::Function(name.clone(), bind_all(args)),
+        Expression::Relation(relop, l_exp, r_exp) => {
+            Expression::Relation(*relop, bind_box(l_exp), bind_box(r_exp))
+        }
+        Expression::Logical(op, l_exp, r_exp) => {
+            Expression::Logical(*op, bind_box(l_exp), bind_box(r_exp))
+        }
+        Expression::Not(e) => Expression::Not(bind_box(e)),
+        Expression::Negate(e) => Expression::Negate(bind_box(e)),
+        Expression::String(_) | Expression::Numeric(_) => exp.clone(),
+
+        // Where the function's body is in the line it's defined in doesn't
+        // matter where it's called, so an error in it marks the call.
+        Expression::Spanned(_, e) => bind_params(e, bindings),
+    }
+}
 
-    match val {
-        Number::Integer(i) => Ok(Number::Integer(i)),
-        Number::Float(f) => Ok(Number::Integer(f as i32)),
+/// Add the names of any user functions called in an expression to a list.
+fn collect_calls(exp: &Expression, calls: &mut Vec<String>) {
+    match exp {
+        Expression::Function(name, args) => {
+            if is_user_function(name) && !calls.contains(name) {
+                calls.push(name.clone());
+            }
+            args.iter().for_each(|a| collect_calls(a, calls));
+        }
+        Expression::Element(_, indices) => indices.iter().for_each(|i| collect_calls(i, calls)),
+        Expression::Operator(_, l_exp, r_exp) => {
+            l_exp
+                .iter()
+                .chain(r_exp.iter())
+                .for_each(|e| collect_calls(e, calls));
+        }
+        Expression::Relation(_, l_exp, r_exp) | Expression::Logical(_, l_exp, r_exp) => {
+            collect_calls(l_exp, calls);
+            collect_calls(r_exp, calls);
+        }
+        Expression::Not(e) | Expression::Negate(e) | Expression::Spanned(_, e) => {
+            collect_calls(e, calls)
+        }
+        Expression::String(_) | Expression::Numeric(_) | Expression::Variable(_) => (),
     }
 }
 
-/// Generate a random float value in the range of 0 to 1.
-fn rnd() -> Number {
-    return Number::Float(rand::random_range(0.0..1.0));
+/// Generate a random float value in the range of 0 to 1 or, given a
+/// number `n`, a random integer from 0 up to but not including `n`.
+fn rnd(args: &[Expression], variables: &mut Variables) -> Result<Value, BasicError> {
+    check_arg_count(RND, args, 0, 1)?;
+
+    let Some(arg) = args.first() else {
+        let f = variables.rng().random_range(0.0..1.0);
+        return Ok(Value::Number(Number::Float(f)));
+    };
+
+    let limit = eval_expression(arg, variables)?.number_value()?;
+    let limit = match limit.float_value() {
+        f if f >= 1.0 && f < i64::MAX as f64 => f as i64,
+        _ => {
+            return Err(BasicError::runtime(
+                ErrorCode::IllegalFunctionCall,
+                format!("Invalid argument {} to function {}", limit, RND),
+            ));
+        }
+    };
+
+    let i = variables.rng().random_range(0..limit);
+    Ok(Value::Number(Number::Integer(i).fitted()))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::expression::ArithOp;
 
     #[test]
     fn evaluates_int() {
-        let args = vec![Expression::Numeric(Number::Float(3.14))];
+        let args = vec![Expression::Numeric(Number::Float(3.75))];
 
-        match eval_function(&String::from("int"), &args, &HashMap::new()) {
+        match eval_function("int", &args, &mut Variables::new()) {
             Ok(res) => {
-                if let Number::Integer(i) = res {
+                if let Value::Number(Number::Integer(i)) = res {
                     assert_eq!(i, 3);
                 }
             }
@@ -74,13 +503,185 @@ mod test {
 
     #[test]
     fn evaluates_rnd() {
-        match eval_function(&String::from("rnd"), &Vec::new(), &HashMap::new()) {
+        match eval_function("rnd", &Vec::new(), &mut Variables::new()) {
             Ok(res) => {
-                if let Number::Float(n) = res {
-                    assert!(n >= 0.0 && n < 1.0);
+                if let Value::Number(Number::Float(n)) = res {
+                    assert!((0.0..1.0).contains(&n));
                 }
             }
             Err(e) => panic!("{}", e),
         }
     }
+
+    #[test]
+    fn evaluates_rnd_with_limit() {
+        let mut variables = Variables::new();
+        let args = vec![Expression::Numeric(Number::Integer(6))];
+
+        for _ in 0..100 {
+            match eval_function("rnd", &args, &mut variables) {
+                Ok(Value::Number(Number::Integer(i))) => assert!((0..6).contains(&i)),
+                Ok(v) => panic!("rnd(6) returned {}", v),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        let zero = vec![Expression::Numeric(Number::Integer(0))];
+        assert!(eval_function("rnd", &zero, &mut variables).is_err());
+    }
+
+    #[test]
+    fn repeats_rnd_with_seed() {
+        let mut variables = Variables::new();
+        let args = vec![Expression::Numeric(Number::Integer(1000000))];
+        let draw = |variables: &mut Variables| -> Vec<Value> {
+            (0..5)
+                .map(|_| eval_function("rnd", &args, variables).unwrap())
+                .collect()
+        };
+
+        variables.set_seed(42);
+        let first = draw(&mut variables);
+
+        // Running a program starts again from the seed.
+        variables.reset();
+        assert_eq!(draw(&mut variables), first);
+
+        variables.randomize(Some(42));
+        assert_eq!(draw(&mut variables), first);
+
+        variables.randomize(Some(43));
+        assert_ne!(draw(&mut variables), first);
+    }
+
+    #[test]
+    fn rejects_string_argument_to_int() {
+        let args = vec![Expression::String(String::from("3"))];
+
+        assert!(eval_function("int", &args, &mut Variables::new()).is_err());
+    }
+
+    fn call(name: &str, args: &[f64]) -> Result<Value, BasicError> {
+        let args: Vec<Expression> = args
+            .iter()
+            .map(|f| Expression::Numeric(Number::Float(*f)))
+            .collect();
+
+        eval_function(name, &args, &mut Variables::new())
+    }
+
+    fn call_float(name: &str, args: &[f64]) -> f64 {
+        match call(name, args) {
+            Ok(Value::Number(n)) => n.float_value(),
+            Ok(v) => panic!("{} returned {}", name, v),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn evaluates_math_functions() {
+        let cases: [(&str, &[f64], f64); 16] = [
+            ("abs", &[-2.5], 2.5),
+            ("sgn", &[-0.1], -1.0),
+            ("sgn", &[0.0], 0.0),
+            ("sqr", &[16.0], 4.0),
+            ("sin", &[0.0], 0.0),
+            ("cos", &[0.0], 1.0),
+            ("tan", &[0.0], 0.0),
+            ("atn", &[1.0], std::f64::consts::FRAC_PI_4),
+            ("log", &[1.0], 0.0),
+            ("exp", &[0.0], 1.0),
+            ("int", &[-2.5], -3.0),
+            ("fix", &[-2.5], -2.0),
+            ("round", &[2.5], 3.0),
+            ("round", &[2.345, 2.0], 2.35),
+            ("min", &[3.0, -1.0, 2.0], -1.0),
+            ("max", &[3.0, -1.0, 2.0], 3.0),
+        ];
+
+        for (name, args, expected) in cases {
+            assert!(
+                (call_float(name, args) - expected).abs() < 1e-9,
+                "{}({:?})",
+                name,
+                args
+            );
+        }
+
+        assert_eq!(call_float("pi", &[]), std::f64::consts::PI);
+    }
+
+    #[test]
+    fn rejects_bad_math_arguments() {
+        assert!(call("sqr", &[-1.0]).is_err());
+        assert!(call("log", &[0.0]).is_err());
+        assert!(call("exp", &[1000.0]).is_err());
+        assert!(call("abs", &[]).is_err());
+        assert!(call("sin", &[1.0, 2.0]).is_err());
+        assert!(call("max", &[1.0]).is_err());
+        assert!(call("pi", &[1.0]).is_err());
+    }
+
+    #[test]
+    fn evaluates_format() {
+        let args = vec![
+            Expression::String(String::from("##.##")),
+            Expression::Numeric(Number::Float(2.5)),
+        ];
+
+        match eval_function("format$", &args, &mut Variables::new()) {
+            Ok(Value::String(s)) => assert_eq!(s, " 2.50"),
+            Ok(_) => panic!("format$ should return a string"),
+            Err(e) => panic!("{}", e),
+        }
+
+        assert!(eval_function("format$", &args[..1], &mut Variables::new()).is_err());
+    }
+
+    #[test]
+    fn calls_user_function() {
+        let mut variables = Variables::new();
+        let x = || Expression::Variable(String::from("x"));
+        let y = || Expression::Variable(String::from("y"));
+
+        // def fna(x, y) = x * y
+        let body =
+            Expression::Operator(ArithOp::Multiply, Some(Box::new(x())), Some(Box::new(y())));
+        define_function(
+            "fna",
+            &[String::from("x"), String::from("y")],
+            &body,
+            &mut variables,
+        )
+        .unwrap();
+
+        variables
+            .set("x", Value::Number(Number::Integer(100)))
+            .unwrap();
+
+        let args = vec![Expression::Numeric(Number::Integer(3)), x()];
+        assert_eq!(
+            eval_function("fna", &args, &mut variables).unwrap(),
+            Value::Number(Number::Integer(300))
+        );
+
+        // The global x is untouched.
+        assert_eq!(
+            variables.get("x").cloned(),
+            Some(Value::Number(Number::Integer(100)))
+        );
+
+        assert!(eval_function("fna", &args[..1], &mut variables).is_err());
+        assert!(eval_function("fnb", &args, &mut variables).is_err());
+    }
+
+    #[test]
+    fn rejects_recursive_functions() {
+        let mut variables = Variables::new();
+        let call = |name: &str| Expression::Function(String::from(name), Vec::new());
+
+        define_function("fna", &[], &call("fnb"), &mut variables).unwrap();
+        assert!(define_function("fnb", &[], &call("fna"), &mut variables).is_err());
+        assert!(define_function("fnc", &[], &call("fnc"), &mut variables).is_err());
+    }
 }
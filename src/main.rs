@@ -1,43 +1,107 @@
 use std::{
-    collections::HashMap,
+    env,
     io::{self},
+    process, thread,
 };
 
 use crate::{
-    errors::BasicError, executor::execute_immediate, expression::Number, parser::SourceReader,
-    program::update_program, statement::Statement,
+    errors::BasicError,
+    executor::{Continuation, STACK_SIZE, execute_immediate, interrupt},
+    number::{IntegerMode, set_integer_mode},
+    parser::SourceReader,
+    program::Program,
+    statement::Statement,
+    variables::{MAX_DEPTH_LIMIT, Variables},
 };
 
 mod errors;
 mod executor;
 mod expression;
+mod format;
 mod function;
+mod number;
+mod output;
 mod parser;
 mod program;
 mod statement;
+mod variables;
 
-fn report_error(err: BasicError, line_num: Option<i32>) {
-    match err {
-        BasicError::SyntaxError(s) => {
-            print!("Syntax error: {}", s);
-            if let Some(n) = line_num {
-                print!(" in line {}", n);
-            }
-            println!()
-        }
-        BasicError::RuntimeError(s) => {
-            print!("Runtime error: {}", s);
-            if let Some(n) = line_num {
-                print!(" in line {}", n);
-            }
-            println!()
+fn report_error(err: BasicError) {
+    output::end_line();
+    println!("{}", err.render());
+}
+
+/// Apply any options given on the command line, e.g. `--max-depth 50`.
+fn apply_options(variables: &mut Variables) -> Result<(), String> {
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // How deeply sub and function calls can be nested.
+            "--max-depth" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) if n <= MAX_DEPTH_LIMIT => variables.set_max_depth(n),
+                Some(Ok(_)) => {
+                    return Err(format!("--max-depth can be at most {}", MAX_DEPTH_LIMIT));
+                }
+                _ => return Err(String::from("--max-depth needs a number")),
+            },
+
+            // How large integers can get.
+            "--integer" => match args.next().and_then(|m| IntegerMode::from_name(&m)) {
+                Some(mode) => set_integer_mode(mode),
+                None => return Err(String::from("--integer needs 32, 64 or big")),
+            },
+
+            // A seed for the random number generator, so that runs can be
+            // repeated.
+            "--seed" => match args.next().map(|n| n.parse::<i64>()) {
+                Some(Ok(n)) => variables.set_seed(n as u64),
+                _ => return Err(String::from("--seed needs a whole number")),
+            },
+
+            _ => return Err(format!("Unknown option {}", arg)),
         }
     }
+
+    Ok(())
 }
 
 fn main() {
-    let mut variables: HashMap<char, Number> = HashMap::new();
-    let mut program: Vec<(i32, Statement)> = Vec::new();
+    // Procedure calls nest on the Rust stack, so the interpreter runs on a
+    // thread with enough of it for the deepest nesting allowed.
+    let interpreter = thread::Builder::new()
+        .name(String::from("interpreter"))
+        .stack_size(STACK_SIZE)
+        .spawn(interpret);
+
+    match interpreter.map(|handle| handle.join()) {
+        Ok(Ok(())) => (),
+        Ok(Err(_)) => process::exit(101),
+        Err(e) => {
+            eprintln!("Can't start the interpreter: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn interpret() {
+    let mut variables = Variables::new();
+
+    if let Err(e) = apply_options(&mut variables) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    // Ctrl-C stops the running program rather than the interpreter, so
+    // that nothing is lost.
+    if let Err(e) = ctrlc::set_handler(interrupt) {
+        eprintln!("Can't handle Ctrl-C: {}", e);
+    }
+
+    let mut program = Program::default();
+
+    // Where a stopped program was up to, so that `cont` can carry on.
+    let mut continuation = Continuation::default();
 
     let mut input_line = String::new();
     println!("Ready.");
@@ -48,6 +112,7 @@ fn main() {
         io::stdin()
             .read_line(&mut input_line)
             .expect("Failed to read line");
+        output::reset_column();
 
         //
         // Parse line
@@ -64,7 +129,7 @@ fn main() {
                 match res {
                     Ok(n) => Some(n),
                     Err(e) => {
-                        report_error(e, None);
+                        report_error(e);
                         continue;
                     }
                 }
@@ -72,45 +137,42 @@ fn main() {
         };
 
         // Build the statement
-        let statement: (Option<i32>, Statement) = match reader.build_statement() {
+        let statement: (Option<i32>, Vec<Statement>) = match reader.build_line() {
             Ok(s) => match line_num {
                 Some(n) => (Some(n), s),
                 None => (None, s),
             },
-            Err(e) => match line_num {
-                Some(n) => {
-                    report_error(e, Some(n));
-                    continue;
-                }
-                None => {
-                    report_error(e, None);
-                    continue;
+            Err(e) => {
+                match line_num {
+                    Some(n) => report_error(e.in_line(n)),
+                    None => report_error(e),
                 }
-            },
+                continue;
+            }
         };
 
         //
         // Run the line or update the program?
         //
 
-        if statement.0.is_some() {
-            // There's a line number, so update the program.
-            update_program(
-                &mut program,
-                (
-                    statement
-                        .0
-                        .expect("Unrecoverable error getting line number"),
-                    statement.1,
-                ),
-            );
+        if let Some(n) = statement.0 {
+            // There's a line number, so update the program. A stopped
+            // program can't be continued once it's been changed.
+            program.update((n, statement.1), &input_line, reader.statement_spans());
+            continuation.program_changed();
         } else {
             // There's no line number, so execute it in immediate mode.
-            match execute_immediate(&statement.1, &mut variables, &mut program) {
+            match execute_immediate(
+                &statement.1,
+                &mut variables,
+                &mut program,
+                &mut continuation,
+            ) {
                 None => (),
-                Some(err) => {
-                    report_error(err, None);
-                }
+                // An error without a line number happened in this line.
+                Some(err) if err.line.is_none() => report_error(err.in_source(&input_line)),
+                // Otherwise it happened in a program line, so show that.
+                Some(err) => report_error(program.with_source(err)),
             }
         }
     }
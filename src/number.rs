@@ -0,0 +1,546 @@
+use std::{
+    cell::Cell,
+    cmp::Ordering,
+    fmt::Display,
+    ops::{Add, Div, Mul, Sub},
+};
+
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, Pow, Signed, ToPrimitive};
+
+use crate::errors::{BasicError, ErrorCode};
+
+/// How large integers can get before arithmetic on them overflows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IntegerMode {
+    /// 32-bit integers, as in earlier versions.
+    #[default]
+    Bits32,
+    /// 64-bit integers.
+    Bits64,
+    /// Integers of any size.
+    Big,
+}
+
+impl IntegerMode {
+    /// Get the mode with the given name: `32`, `64` or `big`.
+    pub fn from_name(name: &str) -> Option<IntegerMode> {
+        match name {
+            "32" => Some(IntegerMode::Bits32),
+            "64" => Some(IntegerMode::Bits64),
+            "big" => Some(IntegerMode::Big),
+            _ => None,
+        }
+    }
+
+    /// Does an integer fit in a plain `Number::Integer` in this mode?
+    fn in_range(&self, i: i128) -> bool {
+        match self {
+            IntegerMode::Bits32 => i32::try_from(i).is_ok(),
+            IntegerMode::Bits64 | IntegerMode::Big => i64::try_from(i).is_ok(),
+        }
+    }
+}
+
+impl Display for IntegerMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegerMode::Bits32 => write!(f, "32"),
+            IntegerMode::Bits64 => write!(f, "64"),
+            IntegerMode::Big => write!(f, "big"),
+        }
+    }
+}
+
+/// The most bits a big integer made by `^` can have.
+const MAX_POWER_BITS: u64 = 1 << 20;
+
+thread_local! {
+    /// The integer mode used by all arithmetic.
+    static INTEGER_MODE: Cell<IntegerMode> = const { Cell::new(IntegerMode::Bits32) };
+}
+
+/// Get the integer mode used by all arithmetic.
+pub fn integer_mode() -> IntegerMode {
+    INTEGER_MODE.with(|m| m.get())
+}
+
+/// Change the integer mode used by all arithmetic. Values already held in
+/// variables keep their current type.
+pub fn set_integer_mode(mode: IntegerMode) {
+    INTEGER_MODE.with(|m| m.set(mode));
+}
+
+/// A numeric value: an integer, an integer too big for 64 bits (only in
+/// big integer mode) or a float.
+#[derive(Clone, Debug)]
+pub enum Number {
+    Integer(i64),
+    Big(BigInt),
+    Float(f64),
+}
+
+impl Number {
+    pub fn is_int(&self) -> bool {
+        matches!(self, Self::Integer(_) | Self::Big(_))
+    }
+
+    pub fn int_value(&self) -> Result<i32, BasicError> {
+        match self {
+            Self::Integer(i) => i32::try_from(*i).map_err(|_| overflow()),
+            Self::Big(_) => Err(overflow()),
+            _ => Err(BasicError::runtime(ErrorCode::TypeMismatch, "Type error")),
+        }
+    }
+
+    /// Get the value as a 32-bit integer (for a subscript, line number and
+    /// so on), discarding any fractional part.
+    pub fn small_int_value(&self) -> Result<i32, BasicError> {
+        match self {
+            Self::Float(f) => float_to_int(*f),
+            _ => self.int_value(),
+        }
+    }
+
+    pub fn float_value(&self) -> f64 {
+        match self {
+            Self::Integer(i) => *i as f64,
+            Self::Big(b) => b.to_f64().unwrap_or(f64::NAN),
+            Self::Float(f) => *f,
+        }
+    }
+
+    /// Get the value of an integer as a big integer.
+    fn big_value(&self) -> Option<BigInt> {
+        match self {
+            Self::Integer(i) => Some(BigInt::from(*i)),
+            Self::Big(b) => Some(b.clone()),
+            Self::Float(_) => None,
+        }
+    }
+
+    /// Read a number typed as input. Integers that are too big for the
+    /// current mode become floats.
+    pub fn parse(text: &str) -> Option<Number> {
+        Number::parse_exact(text).map(Number::fitted)
+    }
+
+    /// Read a number as written in a program, keeping integers of any size
+    /// exactly. They're fitted to the mode when the program is run.
+    pub fn parse_exact(text: &str) -> Option<Number> {
+        let text = text.trim();
+
+        if let Ok(i) = text.parse::<i64>() {
+            return Some(Number::Integer(i));
+        }
+
+        if let Ok(big) = text.parse::<BigInt>() {
+            return Some(Number::Big(big));
+        }
+
+        text.parse::<f64>()
+            .ok()
+            .filter(|f| f.is_finite())
+            .map(Number::Float)
+    }
+
+    /// Negate a number as written in a program. Unlike `negate`, this
+    /// never overflows, since literals are kept exactly.
+    pub fn negate_exact(self) -> Number {
+        match self {
+            Self::Integer(i) => match i.checked_neg() {
+                Some(n) => Self::Integer(n),
+                None => Self::Big(-BigInt::from(i)),
+            },
+            Self::Big(b) => match (-&b).to_i64() {
+                Some(n) => Self::Integer(n),
+                None => Self::Big(-b),
+            },
+            Self::Float(f) => Self::Float(-f),
+        }
+    }
+
+    /// Make sure an integer fits the current mode, turning it into a float
+    /// if it doesn't. This is how literals too big for the mode are read.
+    pub fn fitted(self) -> Number {
+        match &self {
+            Self::Integer(i) if !integer_mode().in_range(*i as i128) => {
+                Number::Float(self.float_value())
+            }
+            Self::Big(_) if integer_mode() != IntegerMode::Big => Number::Float(self.float_value()),
+            _ => self,
+        }
+    }
+
+    /// Convert a float to an integer, discarding any fractional part. A
+    /// value too big for the current mode is an overflow error.
+    pub fn from_float(f: f64) -> Result<Number, BasicError> {
+        let f = f.trunc();
+
+        if f.abs() < 1e38 {
+            return from_i128(f as i128);
+        }
+
+        match BigInt::from_f64(f) {
+            Some(big) => from_big(big),
+            None => Err(overflow()),
+        }
+    }
+
+    /// Negate the number. Negating the smallest integer overflows.
+    pub fn negate(self) -> Result<Self, BasicError> {
+        match self {
+            Self::Integer(i) => from_i128(-(i as i128)),
+            Self::Big(b) => from_big(-b),
+            Self::Float(f) => Ok(Self::Float(-f)),
+        }
+    }
+
+    /// Get the absolute value.
+    pub fn abs(self) -> Result<Self, BasicError> {
+        match self {
+            Self::Integer(i) => from_i128((i as i128).abs()),
+            Self::Big(b) => from_big(b.abs()),
+            Self::Float(f) => Ok(Self::Float(f.abs())),
+        }
+    }
+
+    /// Round to the nearest integer, as `mod` and `\` do with their
+    /// operands.
+    fn rounded(&self) -> Result<Number, BasicError> {
+        match self {
+            Self::Float(f) => Number::from_float(f.round()),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    /// Divide, rounding the operands to integers first and the result
+    /// towards zero.
+    pub fn int_divide(self, rhs: Self) -> Result<Self, BasicError> {
+        match (self.rounded()?, rhs.rounded()?) {
+            (_, r) if r.float_value() == 0.0 => Err(division_by_zero()),
+            (Self::Integer(l), Self::Integer(r)) => from_i128(l as i128 / r as i128),
+            (l, r) => {
+                from_big(l.big_value().unwrap_or_default() / r.big_value().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Get the remainder of dividing, rounding the operands to integers
+    /// first. The result has the same sign as the left operand.
+    pub fn modulus(self, rhs: Self) -> Result<Self, BasicError> {
+        match (self.rounded()?, rhs.rounded()?) {
+            (_, r) if r.float_value() == 0.0 => Err(division_by_zero()),
+            (Self::Integer(l), Self::Integer(r)) => from_i128(l as i128 % r as i128),
+            (l, r) => {
+                from_big(l.big_value().unwrap_or_default() % r.big_value().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Raise to a power. An integer raised to a non-negative integer power
+    /// stays an integer if it fits the current mode, and becomes a float if
+    /// not, just like a literal too big for the mode. In big integer mode, it
+    /// fits unless it would have more than `MAX_POWER_BITS` bits.
+    pub fn power(self, rhs: Self) -> Result<Self, BasicError> {
+        if let (Some(base), Self::Integer(exp)) = (self.big_value(), &rhs)
+            && *exp >= 0
+        {
+            match (&self, integer_mode()) {
+                (_, IntegerMode::Big) => {
+                    // Check roughly how big the result would be first, as a
+                    // huge one would take too long to work out.
+                    if base.bits().saturating_sub(1).saturating_mul(*exp as u64) > MAX_POWER_BITS {
+                        return Err(overflow());
+                    }
+
+                    // Only 0, 1 and -1 can be raised to a power that big, and
+                    // their powers repeat every other step.
+                    let exp = u32::try_from(*exp).unwrap_or(2 + (*exp % 2) as u32);
+                    return from_big(base.pow(exp));
+                }
+                (Self::Integer(b), mode) => {
+                    if let Ok(exp) = u32::try_from(*exp)
+                        && let Some(i) = (*b as i128).checked_pow(exp)
+                        && mode.in_range(i)
+                    {
+                        return from_i128(i);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let result = self.float_value().powf(rhs.float_value());
+        match result.is_nan() {
+            true => Err(BasicError::runtime(
+                ErrorCode::IllegalFunctionCall,
+                format!("Can't raise {} to the power {}", self, rhs),
+            )),
+            false => float_result(result),
+        }
+    }
+}
+
+// Arithmetic on integers is checked, so a result too big for the current
+// integer mode is an error rather than a panic. Floats can't overflow in
+// the same way, but an infinite result is reported as an overflow too.
+
+impl Add for Number {
+    type Output = Result<Self, BasicError>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (Number::Integer(l), Number::Integer(r)) => from_i128(l as i128 + r as i128),
+            (l, r) => match (l.big_value(), r.big_value()) {
+                (Some(l), Some(r)) => from_big(l + r),
+                _ => float_result(l.float_value() + r.float_value()),
+            },
+        }
+    }
+}
+
+impl Sub for Number {
+    type Output = Result<Self, BasicError>;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (Number::Integer(l), Number::Integer(r)) => from_i128(l as i128 - r as i128),
+            (l, r) => match (l.big_value(), r.big_value()) {
+                (Some(l), Some(r)) => from_big(l - r),
+                _ => float_result(l.float_value() - r.float_value()),
+            },
+        }
+    }
+}
+
+impl Mul for Number {
+    type Output = Result<Self, BasicError>;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (Number::Integer(l), Number::Integer(r)) => from_i128(l as i128 * r as i128),
+            (l, r) => match (l.big_value(), r.big_value()) {
+                (Some(l), Some(r)) => from_big(l * r),
+                _ => float_result(l.float_value() * r.float_value()),
+            },
+        }
+    }
+}
+
+impl Div for Number {
+    type Output = Result<Self, BasicError>;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        if rhs.float_value() == 0.0 {
+            return Err(division_by_zero());
+        }
+
+        match (self, rhs) {
+            (Number::Integer(l), Number::Integer(r)) => from_i128(l as i128 / r as i128),
+            (l, r) => match (l.big_value(), r.big_value()) {
+                (Some(l), Some(r)) => from_big(l / r),
+                _ => float_result(l.float_value() / r.float_value()),
+            },
+        }
+    }
+}
+
+// Numbers compare by value, whatever their type, so `1 = 1.0`. Integers
+// are compared exactly, even when they're too big for a float to hold.
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Integer(l), Number::Integer(r)) => l.partial_cmp(r),
+            (l, r) => match (l.big_value(), r.big_value()) {
+                (Some(l), Some(r)) => l.partial_cmp(&r),
+                _ => l.float_value().partial_cmp(&r.float_value()),
+            },
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Integer(n) => write!(f, "{}", n),
+            Number::Big(n) => write!(f, "{}", n),
+            Number::Float(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Turn the result of integer arithmetic into a number, or an overflow
+/// error if it's too big for the current mode.
+fn from_i128(i: i128) -> Result<Number, BasicError> {
+    let mode = integer_mode();
+
+    match mode.in_range(i) {
+        true => Ok(Number::Integer(i as i64)),
+        false if mode == IntegerMode::Big => Ok(Number::Big(BigInt::from(i))),
+        false => Err(overflow()),
+    }
+}
+
+/// Turn the result of big integer arithmetic into a number. A result small
+/// enough for 64 bits becomes a plain integer again.
+fn from_big(big: BigInt) -> Result<Number, BasicError> {
+    match big.to_i128() {
+        Some(i) if i64::try_from(i).is_ok() => from_i128(i),
+        _ if integer_mode() == IntegerMode::Big => Ok(Number::Big(big)),
+        _ => Err(overflow()),
+    }
+}
+
+/// Turn the result of a float operation into a number, or an overflow
+/// error if it's infinite or not a number.
+fn float_result(result: f64) -> Result<Number, BasicError> {
+    match result.is_finite() {
+        true => Ok(Number::Float(result)),
+        false => Err(overflow()),
+    }
+}
+
+/// Convert a float to a 32-bit integer, discarding any fractional part. A
+/// value too big for 32 bits is an overflow error.
+pub fn float_to_int(f: f64) -> Result<i32, BasicError> {
+    match f.is_finite() && f.trunc() >= i32::MIN as f64 && f.trunc() <= i32::MAX as f64 {
+        true => Ok(f as i32),
+        false => Err(overflow()),
+    }
+}
+
+/// The error raised when a result is too big to represent.
+pub fn overflow() -> BasicError {
+    BasicError::runtime(ErrorCode::Overflow, "Overflow")
+}
+
+/// The error raised when dividing by zero.
+pub fn division_by_zero() -> BasicError {
+    BasicError::runtime(ErrorCode::DivisionByZero, "Division by zero")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run some code in the given integer mode, restoring the default
+    /// afterwards.
+    fn in_mode<T>(mode: IntegerMode, f: impl FnOnce() -> T) -> T {
+        set_integer_mode(mode);
+        let result = f();
+        set_integer_mode(IntegerMode::default());
+        result
+    }
+
+    fn int(i: i64) -> Number {
+        Number::Integer(i)
+    }
+
+    #[test]
+    fn overflows_by_mode() {
+        let big = || int(i32::MAX as i64) * int(4);
+
+        assert!(in_mode(IntegerMode::Bits32, big).is_err());
+        assert_eq!(in_mode(IntegerMode::Bits64, big).unwrap(), int(8589934588));
+
+        let huge = || int(i64::MAX) + int(1);
+        assert!(in_mode(IntegerMode::Bits64, huge).is_err());
+
+        match in_mode(IntegerMode::Big, huge) {
+            Ok(Number::Big(b)) => assert_eq!(b.to_string(), "9223372036854775808"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn raises_to_powers_by_mode() {
+        // A power too big for the mode becomes a float, like a literal.
+        let power = || int(2).power(int(40));
+        assert!(matches!(
+            in_mode(IntegerMode::Bits32, power),
+            Ok(Number::Float(f)) if f == 1099511627776.0
+        ));
+        assert!(matches!(
+            in_mode(IntegerMode::Bits64, power),
+            Ok(Number::Integer(1099511627776))
+        ));
+        assert!(matches!(
+            in_mode(IntegerMode::Bits64, || int(-3).power(int(41))),
+            Ok(Number::Float(_))
+        ));
+
+        // Only a float too big to hold is an overflow.
+        assert!(int(10).power(int(400)).is_err());
+        assert!(int(2).power(int(5000000000)).is_err());
+
+        in_mode(IntegerMode::Big, || {
+            assert_eq!(int(-1).power(int(5000000001)).unwrap(), int(-1));
+            assert!(matches!(
+                int(0).power(int(5000000000)),
+                Ok(Number::Integer(0))
+            ));
+        });
+    }
+
+    #[test]
+    fn calculates_with_big_integers() {
+        in_mode(IntegerMode::Big, || {
+            let factorial = (1..=25).fold(int(1), |acc, n| (acc * int(n)).unwrap());
+            assert_eq!(factorial.to_string(), "15511210043330985984000000");
+
+            // Results small enough for 64 bits become plain integers again.
+            let back = (factorial.clone() / factorial.clone()).unwrap();
+            assert!(matches!(back, Number::Integer(1)));
+
+            assert_eq!(
+                int(2).power(int(100)).unwrap().to_string(),
+                "1267650600228229401496703205376"
+            );
+            assert!(matches!(
+                int(10).power(int(2000000000)),
+                Err(BasicError {
+                    code: ErrorCode::Overflow,
+                    ..
+                })
+            ));
+            assert!(factorial > int(i64::MAX));
+            assert!(factorial.clone().negate().unwrap() < int(i64::MIN));
+        });
+    }
+
+    #[test]
+    fn compares_by_value() {
+        assert_eq!(int(1), Number::Float(1.0));
+        assert!(int(2) > Number::Float(1.5));
+        assert!(Number::Float(-0.5) < int(0));
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(Number::parse("42"), Some(int(42)));
+        assert_eq!(Number::parse("-1.5"), Some(Number::Float(-1.5)));
+        assert_eq!(Number::parse("x"), None);
+
+        // Integers too big for the mode are read as floats.
+        assert!(matches!(
+            Number::parse("3000000000"),
+            Some(Number::Float(_))
+        ));
+        assert!(matches!(
+            in_mode(IntegerMode::Bits64, || Number::parse("3000000000")),
+            Some(Number::Integer(3000000000))
+        ));
+        assert!(matches!(
+            in_mode(IntegerMode::Big, || Number::parse("99999999999999999999")),
+            Some(Number::Big(_))
+        ));
+    }
+}
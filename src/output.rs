@@ -0,0 +1,108 @@
+use std::{
+    cell::Cell,
+    io::{self, Write},
+};
+
+/// The width of each print zone. A `,` in a print statement moves to the
+/// start of the next zone.
+pub const ZONE_WIDTH: usize = 14;
+
+/// The furthest `tab` or `spc` can move in one go.
+pub const MAX_COUNT: usize = 255;
+
+thread_local! {
+    /// The column the next character will be printed in, counting from 0.
+    static COLUMN: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Get the column the next character will be printed in, counting from 0.
+pub fn column() -> usize {
+    COLUMN.with(|c| c.get())
+}
+
+/// Print some text to the console without a newline, keeping track of the
+/// column.
+pub fn write(text: &str) {
+    print!("{}", text);
+
+    let new_column = match text.rfind('\n') {
+        Some(idx) => text[idx + 1..].chars().count(),
+        None => column() + text.chars().count(),
+    };
+
+    COLUMN.with(|c| c.set(new_column));
+}
+
+/// End the current line.
+pub fn newline() {
+    println!();
+    reset_column();
+}
+
+/// End the current line, unless nothing has been printed on it yet.
+pub fn end_line() {
+    if column() > 0 {
+        newline();
+    }
+}
+
+/// Note that the cursor is at the start of a line, e.g. after the user has
+/// typed a line of input.
+pub fn reset_column() {
+    COLUMN.with(|c| c.set(0));
+}
+
+/// Make sure everything printed so far appears on the console.
+pub fn flush() {
+    io::stdout().flush().unwrap();
+}
+
+/// Move to the given column, counting from 1 as `tab` does. If the
+/// column has already been passed, move to it on the next line.
+pub fn tab(position: usize) {
+    let target = position.saturating_sub(1);
+
+    if column() > target {
+        newline();
+    }
+
+    spaces(target - column());
+}
+
+/// Print the given number of spaces.
+pub fn spaces(count: usize) {
+    write(&" ".repeat(count));
+}
+
+/// Move to the start of the next print zone.
+pub fn next_zone() {
+    spaces(ZONE_WIDTH - column() % ZONE_WIDTH);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_column() {
+        reset_column();
+
+        write("abc");
+        assert_eq!(column(), 3);
+
+        next_zone();
+        assert_eq!(column(), ZONE_WIDTH);
+
+        tab(20);
+        assert_eq!(column(), 19);
+
+        tab(5);
+        assert_eq!(column(), 4);
+
+        write("x\nyz");
+        assert_eq!(column(), 2);
+
+        end_line();
+        assert_eq!(column(), 0);
+    }
+}
@@ -1,9 +1,11 @@
 use std::char;
 
 use crate::{
-    errors::BasicError,
-    expression::{ArithOp, Condition, Expression, Number, Relop},
-    statement::Statement,
+    errors::{BasicError, Span},
+    expression::{ArithOp, Expression, LogicalOp, Relop, Target, Value, is_string_var},
+    function::{is_function, is_user_function},
+    number::{IntegerMode, Number},
+    statement::{PrintItem, Statement},
 };
 
 // Keywords as they appear in source code.
@@ -26,6 +28,48 @@ pub const LOAD: &str = "load";
 pub const SAVE: &str = "save";
 pub const CLEAR: &str = "clear";
 pub const END: &str = "end";
+pub const DIM: &str = "dim";
+pub const ELSE: &str = "else";
+pub const ELSEIF: &str = "elseif";
+pub const AND: &str = "and";
+pub const OR: &str = "or";
+pub const NOT: &str = "not";
+pub const WHILE: &str = "while";
+pub const WEND: &str = "wend";
+pub const DO: &str = "do";
+pub const LOOP: &str = "loop";
+pub const UNTIL: &str = "until";
+pub const ON: &str = "on";
+pub const DEF: &str = "def";
+pub const SUB: &str = "sub";
+pub const FUNCTION: &str = "function";
+pub const CALL: &str = "call";
+pub const DATA: &str = "data";
+pub const READ: &str = "read";
+pub const RESTORE: &str = "restore";
+pub const TAB: &str = "tab";
+pub const SPC: &str = "spc";
+pub const USING: &str = "using";
+pub const MOD: &str = "mod";
+pub const INTEGER: &str = "integer";
+pub const RANDOMIZE: &str = "randomize";
+pub const ERROR: &str = "error";
+pub const RESUME: &str = "resume";
+pub const STOP: &str = "stop";
+pub const CONT: &str = "cont";
+
+/// All keywords. None of these can be used as a variable name.
+const KEYWORDS: [&str; 48] = [
+    REM, PRINT, LET, GOTO, IF, THEN, INPUT, GOSUB, RETURN, FOR, TO, STEP, NEXT, LIST, RUN, LOAD,
+    SAVE, CLEAR, DIM, END, ELSE, ELSEIF, AND, OR, NOT, WHILE, WEND, DO, LOOP, UNTIL, ON, DEF, SUB,
+    FUNCTION, CALL, DATA, READ, RESTORE, TAB, SPC, USING, MOD, INTEGER, RANDOMIZE, ERROR, RESUME,
+    STOP, CONT,
+];
+
+/// Is the given word a keyword?
+pub fn is_keyword(word: &str) -> bool {
+    KEYWORDS.contains(&word)
+}
 
 /// A structure used to track the parsing of a single statement.
 pub struct SourceReader {
@@ -38,6 +82,9 @@ pub struct SourceReader {
     /// The depth into any expression in a statement. Must be 0 for a
     /// balanced expression.
     exp_depth: usize,
+
+    /// Where each statement read by `build_line` is in the line.
+    spans: Vec<Span>,
 }
 
 impl SourceReader {
@@ -46,9 +93,15 @@ impl SourceReader {
             line: src_line,
             idx: 0,
             exp_depth: 0,
+            spans: Vec::new(),
         }
     }
 
+    /// Get where each statement read by `build_line` is in the line.
+    pub fn statement_spans(&self) -> &[Span] {
+        &self.spans
+    }
+
     /// Get a value that can only be a whole number (e.g. line number)
     pub fn get_integer(&mut self) -> Result<i32, BasicError> {
         self.skip_ws();
@@ -63,9 +116,7 @@ impl SourceReader {
 
         match self.line[start_at..end_at].parse::<i32>() {
             Ok(i) => Ok(i),
-            Err(_) => Err(BasicError::SyntaxError(String::from(
-                "Error reading number",
-            ))),
+            Err(_) => Err(BasicError::syntax("Error reading number")),
         }
     }
 
@@ -81,25 +132,51 @@ impl SourceReader {
         let end_at = self.idx;
         self.skip_ws();
 
-        match self.line[start_at..end_at].parse::<i32>() {
-            Ok(i) => Ok(Number::Integer(i)),
-            Err(_) => match self.line[start_at..end_at].parse::<f64>() {
-                Ok(f) => Ok(Number::Float(f)),
-                Err(_) => Err(BasicError::SyntaxError(String::from(
-                    "Error reading number",
-                ))),
-            },
+        Number::parse_exact(&self.line[start_at..end_at])
+            .ok_or_else(|| BasicError::syntax("Error reading number"))
+    }
+
+    /// Get a literal string or number (which may be signed) at the current
+    /// point in the line.
+    fn get_literal(&mut self) -> Result<Value, BasicError> {
+        self.skip_ws();
+
+        if self.ch() == '"' {
+            let text = self.get_string()?.to_string();
+            self.skip_ws();
+            return Ok(Value::String(text));
+        }
+
+        let negative = self.ch() == '-';
+        if negative || self.ch() == '+' {
+            self.next();
+        }
+
+        if !self.is_digit() && self.ch() != '.' {
+            return Err(BasicError::syntax("Expected a number or string"));
+        }
+
+        match negative {
+            true => Ok(Value::Number(self.get_number()?.negate_exact())),
+            false => Ok(Value::Number(self.get_number()?)),
         }
     }
 
-    /// Get a keyword at the current point in the line. A keyword is made
-    /// up purely of alphabetic characters.
+    /// Get a keyword at the current point in the line. The whole word is
+    /// read, as for an identifier, so that a variable whose name starts
+    /// with a keyword (e.g. `end1`) isn't taken for the keyword.
     fn get_keyword(&mut self) -> &str {
         self.skip_ws();
         let start_at = self.idx;
 
-        while self.is_alpha() {
-            self.next();
+        if self.is_alpha() {
+            while self.is_alpha() || self.is_digit() {
+                self.next();
+            }
+
+            if self.ch() == '$' {
+                self.next();
+            }
         }
 
         let end_at = self.idx;
@@ -114,7 +191,7 @@ impl SourceReader {
         self.skip_ws();
 
         if self.ch() != '"' {
-            return Err(BasicError::SyntaxError(String::from("Expected \"")));
+            return Err(BasicError::syntax("Expected \""));
         }
 
         // Skip quote mark
@@ -125,7 +202,7 @@ impl SourceReader {
         while self.ch() != '"' {
             // Have we prematurely reached the end of the line?
             if self.at_end() || self.ch() == '\n' {
-                return Err(BasicError::SyntaxError(String::from("Unterminated string")));
+                return Err(BasicError::syntax("Unterminated string"));
             }
 
             self.next();
@@ -150,21 +227,128 @@ impl SourceReader {
         Ok(text)
     }
 
-    /// Get a single character at the current point in the line.
-    fn get_char(&mut self) -> Result<char, BasicError> {
+    /// Get an identifier (the name of a variable, array or function) at
+    /// the current point in the line. An identifier starts with a letter,
+    /// followed by any number of letters and digits, and ends with `$` if
+    /// it refers to a string.
+    fn get_identifier(&mut self) -> Result<String, BasicError> {
         self.skip_ws();
 
-        if self.is_alpha() {
-            let c = self.ch();
+        if !self.is_alpha() {
+            return Err(BasicError::syntax("Expected an identifier"));
+        }
+
+        let mut identifier = String::new();
+
+        while self.is_alpha() || self.is_digit() {
+            identifier.push(self.ch());
             self.next();
-            return Ok(c);
-        } else {
-            Err(BasicError::SyntaxError(String::from(
-                "Failed to match a character",
-            )))
+        }
+
+        if self.ch() == '$' {
+            identifier.push('$');
+            self.next();
+        }
+
+        self.skip_ws();
+
+        Ok(identifier)
+    }
+
+    /// Get a variable name at the current point in the line. Keywords and
+    /// function names can't be used as variable names.
+    fn get_var_name(&mut self) -> Result<String, BasicError> {
+        let name = self.get_identifier()?;
+        let word = name.trim_end_matches('$');
+
+        if is_keyword(word) || is_function(&name) {
+            return Err(BasicError::syntax(format!(
+                "{} is a reserved word and can't be used as a variable",
+                name
+            )));
+        }
+
+        Ok(name)
+    }
+
+    /// Get the target of an assignment at the current point in the line:
+    /// either a variable name or an array element, e.g. `a(i, j)`.
+    fn get_target(&mut self) -> Result<Target, BasicError> {
+        let name = self.get_var_name()?;
+
+        if self.ch() == '(' {
+            return Ok(Target::Element(name, self.get_args()?));
+        }
+
+        Ok(Target::Variable(name))
+    }
+
+    /// Get a parenthesised, comma-separated list of expressions at the
+    /// current point in the line (e.g. function arguments or array
+    /// subscripts).
+    fn get_args(&mut self) -> Result<Vec<Expression>, BasicError> {
+        let mut args: Vec<Expression> = Vec::new();
+        self.skip_token("(")?;
+
+        while self.ch() != ')' {
+            args.push(self.get_expression()?);
+            self.skip_ws();
+
+            if self.ch() != ')' {
+                self.skip_token(",")?;
+            }
+        }
+
+        self.skip_token(")")?;
+
+        Ok(args)
+    }
+
+    /// Get the argument list of something that takes exactly one argument,
+    /// such as `tab(n)`.
+    fn get_single_arg(&mut self, name: &str) -> Result<Expression, BasicError> {
+        let mut args = self.get_args()?;
+
+        match args.len() {
+            1 => Ok(args.remove(0)),
+            _ => Err(BasicError::syntax(format!(
+                "Expected one argument to {}",
+                name
+            ))),
         }
     }
 
+    /// Get the parameter list of a function or procedure definition at the
+    /// current point in the line, e.g. `(a, b$)`. The list is optional when
+    /// there are no parameters.
+    fn get_params(&mut self) -> Result<Vec<String>, BasicError> {
+        let mut params: Vec<String> = Vec::new();
+        self.skip_ws();
+
+        if self.ch() != '(' {
+            return Ok(params);
+        }
+
+        self.skip_token("(")?;
+
+        while self.ch() != ')' {
+            let param = self.get_var_name()?;
+            if params.contains(&param) {
+                return Err(BasicError::syntax(format!("Duplicate parameter {}", param)));
+            }
+            params.push(param);
+            self.skip_ws();
+
+            if self.ch() != ')' {
+                self.skip_token(",")?;
+            }
+        }
+
+        self.skip_token(")")?;
+
+        Ok(params)
+    }
+
     /// Get a relative oeprator at the current point in the line.
     fn get_relop(&mut self) -> Result<Relop, BasicError> {
         self.skip_ws();
@@ -177,10 +361,7 @@ impl SourceReader {
             relop.push(self.ch());
             self.next();
 
-            if self.ch() == '=' {
-                relop.push(self.ch());
-                self.next();
-            } else if self.ch() == '>' {
+            if self.ch() == '=' || self.ch() == '>' {
                 relop.push(self.ch());
                 self.next();
             }
@@ -203,150 +384,302 @@ impl SourceReader {
             "<=" => Ok(Relop::LTE),
             ">=" => Ok(Relop::GTE),
             "<>" => Ok(Relop::NEQ),
-            _ => Err(BasicError::SyntaxError(String::from(
-                "Relative operator not recognised",
-            ))),
+            _ => Err(BasicError::syntax("Relative operator not recognised")),
         }
     }
 
-    /// Get a numeric expression at the current point in the line.
+    /// Get an expression at the current point in the line. From loosest to
+    /// tightest binding, an expression is made up of: `or`, `and`, `not`,
+    /// relative operators, addops, `mod`, integer division, mulops, unary
+    /// minus and plus, `^` and factors.
     fn get_expression(&mut self) -> Result<Expression, BasicError> {
-        let mut root = self.get_term()?;
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_conjunction()?;
+
+        while self.peek_keyword(OR) {
+            self.skip_token(OR)?;
+            let conjunction = self.get_conjunction()?;
+            let logical = Expression::Logical(LogicalOp::Or, Box::new(root), Box::new(conjunction));
+            root = self.spanned(start_at, logical);
+        }
+
+        Ok(root)
+    }
+
+    fn get_conjunction(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_negation()?;
+
+        while self.peek_keyword(AND) {
+            self.skip_token(AND)?;
+            let negation = self.get_negation()?;
+            let logical = Expression::Logical(LogicalOp::And, Box::new(root), Box::new(negation));
+            root = self.spanned(start_at, logical);
+        }
+
+        Ok(root)
+    }
+
+    fn get_negation(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+
+        if self.peek_keyword(NOT) {
+            self.skip_token(NOT)?;
+            let negation = Expression::Not(Box::new(self.get_negation()?));
+            return Ok(self.spanned(start_at, negation));
+        }
+
+        self.get_relation()
+    }
+
+    fn get_relation(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_sum()?;
+
+        while self.ch() == '=' || self.ch() == '<' || self.ch() == '>' {
+            let relop = self.get_relop()?;
+            let sum = self.get_sum()?;
+            let relation = Expression::Relation(relop, Box::new(root), Box::new(sum));
+            root = self.spanned(start_at, relation);
+        }
+
+        Ok(root)
+    }
+
+    fn get_sum(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_modulus()?;
 
         while !self.at_end() {
             self.skip_ws();
 
-            if self.is_addop() {
-                if self.ch() == '+' {
-                    self.skip_token(String::from("+"));
-                    let term = self.get_term()?;
-                    root = self.make_subtree(ArithOp::Add, root, term);
-                } else if self.ch() == '-' {
-                    self.skip_token(String::from('-'));
-                    let term = self.get_term()?;
-                    root = self.make_subtree(ArithOp::Subtract, root, term);
-                }
-            } else if self.ch() == ')' && self.exp_depth > 0 {
-                self.skip_token(String::from(")"));
-                self.exit_subexp();
+            if self.ch() == '+' {
+                self.skip_token("+")?;
+                let modulus = self.get_modulus()?;
+                root = self.make_subtree(start_at, ArithOp::Add, root, modulus);
+            } else if self.ch() == '-' {
+                self.skip_token("-")?;
+                let modulus = self.get_modulus()?;
+                root = self.make_subtree(start_at, ArithOp::Subtract, root, modulus);
+            } else {
                 break;
+            }
+        }
+
+        Ok(root)
+    }
+
+    fn get_modulus(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_quotient()?;
+
+        while self.peek_keyword(MOD) {
+            self.skip_token(MOD)?;
+            let quotient = self.get_quotient()?;
+            root = self.make_subtree(start_at, ArithOp::Modulus, root, quotient);
+        }
+
+        Ok(root)
+    }
+
+    fn get_quotient(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_term()?;
+
+        while !self.at_end() {
+            self.skip_ws();
+
+            if self.ch() == '\\' {
+                self.skip_token("\\")?;
+                let term = self.get_term()?;
+                root = self.make_subtree(start_at, ArithOp::IntDivide, root, term);
             } else {
                 break;
             }
         }
 
-        return Ok(root);
+        Ok(root)
     }
 
     fn get_term(&mut self) -> Result<Expression, BasicError> {
-        let mut root = self.get_factor()?;
+        self.skip_ws();
+        let start_at = self.idx;
+        let mut root = self.get_unary()?;
 
         while !self.at_end() {
             self.skip_ws();
 
-            if self.is_mulop() {
-                if self.ch() == '*' {
-                    self.skip_token(String::from("*"));
-                    let factor = self.get_factor()?;
-                    root = self.make_subtree(ArithOp::Multiply, root, factor);
-                } else if self.ch() == '/' {
-                    self.skip_token(String::from("/"));
-                    let factor = self.get_factor()?;
-                    root = self.make_subtree(ArithOp::Divide, root, factor);
-                }
+            if self.ch() == '*' {
+                self.skip_token("*")?;
+                let unary = self.get_unary()?;
+                root = self.make_subtree(start_at, ArithOp::Multiply, root, unary);
+            } else if self.ch() == '/' {
+                self.skip_token("/")?;
+                let unary = self.get_unary()?;
+                root = self.make_subtree(start_at, ArithOp::Divide, root, unary);
             } else {
                 break;
             }
         }
 
-        return Ok(root);
+        Ok(root)
     }
 
-    fn get_factor(&mut self) -> Result<Expression, BasicError> {
-        if self.at_end() {
-            return Err(BasicError::SyntaxError(String::from(
-                "Unexpected end of line",
-            )));
-        }
+    /// Get a value with an optional unary minus or plus. A minus binds
+    /// more loosely than `^`, so `-2^2` is -4, and a minus directly in
+    /// front of a number just makes a negative number.
+    fn get_unary(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
 
-        // Is it a variable or function call?
-        if self.is_alpha() {
-            let mut identifier = String::new();
+        if self.ch() == '+' {
+            self.skip_token("+")?;
+            return self.get_unary();
+        }
 
-            // Get first letter
-            identifier.push_str(&self.ch().to_string());
+        if self.ch() == '-' {
+            let start_at = self.idx;
+            self.skip_token("-")?;
 
-            // Any more alphabetic characters?
-            self.next();
-            if self.is_alpha() {
-                // Yes, so it must be a function call.
-                while self.is_alpha() {
-                    identifier.push_str(&self.get_char()?.to_string());
+            return match self.get_unary()? {
+                Expression::Numeric(n) if n.float_value() >= 0.0 => {
+                    Ok(Expression::Numeric(n.negate_exact()))
                 }
+                exp => Ok(self.spanned(start_at, Expression::Negate(Box::new(exp)))),
+            };
+        }
 
-                // Get args
-                let mut args: Vec<Expression> = Vec::new();
-                self.skip_token("(".to_string());
+        self.get_power()
+    }
 
-                while self.ch() != ')' {
-                    args.push(self.get_expression()?);
-                    self.skip_ws();
+    /// Get a power, which groups from right to left, so `2^3^2` is
+    /// `2^(3^2)`. The exponent may have its own unary minus, as in `2^-1`.
+    fn get_power(&mut self) -> Result<Expression, BasicError> {
+        self.skip_ws();
+        let start_at = self.idx;
+        let base = self.get_factor()?;
+        self.skip_ws();
 
-                    if self.ch() != ',' {
-                        self.skip_token(",".to_string());
-                    }
-                }
+        if self.ch() == '^' {
+            self.skip_token("^")?;
+            let exponent = self.get_unary()?;
+            return Ok(self.make_subtree(start_at, ArithOp::Power, base, exponent));
+        }
 
-                self.skip_token(")".to_string());
+        Ok(base)
+    }
 
-                return Ok(Expression::Function(identifier, args));
-            }
+    fn get_factor(&mut self) -> Result<Expression, BasicError> {
+        if self.at_end() {
+            return Err(BasicError::syntax("Unexpected end of line"));
+        }
 
-            // If we reach here, there was only one letter, so it's a variable
-            return Ok(Expression::Variable(
-                identifier.chars().nth(0).expect("Error reading identifier"),
-            ));
+        // Is it a string literal?
+        if self.ch() == '"' {
+            let s = self.get_string()?.to_string();
+            return Ok(Expression::String(s));
         }
 
-        // Is it a (possibly negative) number?
-        let mut adjust: Number = Number::Integer(1);
-        if self.ch() == '-' {
-            self.skip_token(String::from("-"));
-            adjust = Number::Integer(-1);
+        // Is it a variable, array element or function call?
+        if self.is_alpha() {
+            let start_at = self.idx;
+            let identifier = self.get_identifier()?;
+
+            // Function names are reserved, so this must be a function call.
+            // The brackets can be left off when there are no arguments, as
+            // in `pi`.
+            if is_function(&identifier) {
+                let args = match self.ch() == '(' {
+                    true => self.get_args()?,
+                    false => Vec::new(),
+                };
+                let function = Expression::Function(identifier, args);
+                return Ok(self.spanned(start_at, function));
+            }
+
+            if is_keyword(identifier.trim_end_matches('$')) {
+                return Err(BasicError::syntax(format!(
+                    "Unexpected keyword {}",
+                    identifier
+                )));
+            }
+
+            // Otherwise it's an array element if followed by subscripts...
+            if self.ch() == '(' {
+                let element = Expression::Element(identifier, self.get_args()?);
+                return Ok(self.spanned(start_at, element));
+            }
+
+            // ...or a simple variable if not.
+            return Ok(self.spanned(start_at, Expression::Variable(identifier)));
         }
 
+        // Is it a number?
         if self.is_digit() {
-            return Ok(Expression::Numeric(self.get_number()? * adjust));
+            return Ok(Expression::Numeric(self.get_number()?));
         }
 
         // Is it a subexpression?
         if self.ch() == '(' {
-            self.skip_token(String::from("("));
+            self.skip_token("(")?;
             self.enter_subexp();
 
-            return Ok(self.get_expression()?);
+            let exp = self.get_expression()?;
+
+            self.skip_token(")")?;
+            self.exit_subexp()?;
+
+            return Ok(exp);
         }
 
-        Err(BasicError::SyntaxError(String::from("Error in expression")))
+        Err(BasicError::syntax("Error in expression"))
     }
 
     /// Join two child expressions to a parent operator to produce a
-    /// binary subtree.
+    /// binary subtree, which starts at the given index in the line.
     fn make_subtree(
         &self,
+        start_at: usize,
         operator: ArithOp,
         l_child: Expression,
         r_child: Expression,
     ) -> Expression {
-        Expression::Operator(operator, Some(Box::new(l_child)), Some(Box::new(r_child)))
+        let subtree =
+            Expression::Operator(operator, Some(Box::new(l_child)), Some(Box::new(r_child)));
+        self.spanned(start_at, subtree)
+    }
+
+    /// Get where in the line the text from the given index up to the
+    /// current point is, leaving out any whitespace after it.
+    fn span_from(&self, start_at: usize) -> Span {
+        let text: String = self
+            .line
+            .chars()
+            .skip(start_at)
+            .take(self.idx.saturating_sub(start_at))
+            .collect();
+
+        Span {
+            column: start_at + 1,
+            width: text.trim_end().chars().count(),
+        }
+    }
+
+    /// Note where in the line an expression that starts at the given index
+    /// is, so that an error in it can be marked.
+    fn spanned(&self, start_at: usize, exp: Expression) -> Expression {
+        Expression::Spanned(self.span_from(start_at), Box::new(exp))
     }
 
     /// Get the character at the current point in the line.
     fn ch(&self) -> char {
-        match self.line.chars().nth(self.idx) {
-            Some(c) => return c,
-            None => return '\0',
-        }
+        self.line.chars().nth(self.idx).unwrap_or('\0')
     }
 
     /// Have we reached the end of line?
@@ -354,6 +687,21 @@ impl SourceReader {
         self.idx >= self.line.len()
     }
 
+    /// Have we reached the end of the current statement (i.e. the end of the
+    /// line, a ':' separator or an else)?
+    fn at_end_of_statement(&self) -> bool {
+        self.at_end() || self.ch() == ':' || self.peek_keyword(ELSE)
+    }
+
+    /// Does the given keyword come next in the line? It mustn't just be the
+    /// start of a longer identifier.
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        let rest = self.line[self.idx.min(self.line.len())..].trim_start();
+
+        rest.starts_with(keyword)
+            && !rest[keyword.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '$')
+    }
+
     /// Move to the next character in the line.
     fn next(&mut self) {
         self.idx += 1;
@@ -369,34 +717,33 @@ impl SourceReader {
 
     /// Skip past the specified token. If the specified token isn't found
     /// at the current point in the line, generate an error.
-    fn skip_token(&mut self, token: String) -> Option<BasicError> {
-        // TODO Change token to &str?
+    fn skip_token(&mut self, token: &str) -> Result<(), BasicError> {
         self.skip_ws();
 
         for c in token.chars() {
             if self.ch() != c {
-                return Some(BasicError::SyntaxError(format!("Expected {}", token)));
+                return Err(BasicError::syntax(format!("Expected {}", token)));
             }
             self.next();
         }
 
         self.skip_ws();
 
-        None
+        Ok(())
     }
 
     fn enter_subexp(&mut self) {
         self.exp_depth += 1;
     }
 
-    fn exit_subexp(&mut self) -> Option<BasicError> {
+    fn exit_subexp(&mut self) -> Result<(), BasicError> {
         if self.exp_depth > 0 {
             self.exp_depth -= 1;
         } else {
-            return Some(BasicError::SyntaxError(String::from("Too many ')'")));
+            return Err(BasicError::syntax("Too many ')'"));
         }
 
-        None
+        Ok(())
     }
 
     fn is_space(&self) -> bool {
@@ -404,102 +751,309 @@ impl SourceReader {
     }
 
     pub fn is_digit(&self) -> bool {
-        self.ch().is_digit(10)
+        self.ch().is_ascii_digit()
     }
 
     fn is_alpha(&self) -> bool {
         self.ch().is_alphabetic()
     }
 
-    fn is_addop(&self) -> bool {
-        self.ch() == '+' || self.ch() == '-'
-    }
-
-    fn is_mulop(&self) -> bool {
-        self.ch() == '*' || self.ch() == '/'
-    }
-
     /// Compile the current line into a Statement.
     pub fn build_statement(&mut self) -> Result<Statement, BasicError> {
-        let keyword = self.get_keyword();
+        self.skip_ws();
+        let start_at = self.idx;
+        let keyword = self.get_keyword().to_string();
 
-        let statement = match keyword {
+        let statement = match keyword.as_str() {
             REM => {
                 let comment = self.get_text()?;
                 Ok(Statement::Rem(comment))
             }
 
-            PRINT => {
-                let mut another = true;
-                let mut args: Vec<Expression> = Vec::new();
+            PRINT if self.peek_keyword(USING) => {
+                self.skip_token(USING)?;
+                let format = self.get_expression()?;
+                self.skip_token(";")?;
+
+                // The values may be separated by either `,` or `;`, and a
+                // separator at the end keeps the output on the same line.
+                let mut exps: Vec<Expression> = Vec::new();
+                let mut newline = true;
 
-                while another {
-                    another = false;
+                while !self.at_end_of_statement() {
+                    exps.push(self.get_expression()?);
+                    self.skip_ws();
 
-                    if self.ch() == '"' {
-                        let msg = self.get_string()?.to_string();
-                        args.push(Expression::String(msg));
-                    } else if !self.at_end() {
-                        args.push(self.get_expression()?);
+                    newline = !matches!(self.ch(), ',' | ';');
+                    if newline {
+                        break;
                     }
+                    self.next();
+                    self.skip_ws();
+                }
+
+                if exps.is_empty() {
+                    return Err(BasicError::syntax("Expected values after print using"));
+                }
+
+                Ok(Statement::PrintUsing(format, exps, newline))
+            }
+
+            PRINT => {
+                let mut items: Vec<PrintItem> = Vec::new();
 
+                loop {
                     self.skip_ws();
 
-                    // More?
-                    if self.ch() == ',' {
-                        another = true;
-                        self.skip_token(String::from(","));
-                        self.skip_ws();
+                    if self.at_end_of_statement() {
+                        break;
                     }
+
+                    let item = if self.ch() == ';' {
+                        self.skip_token(";")?;
+                        PrintItem::Join
+                    } else if self.ch() == ',' {
+                        self.skip_token(",")?;
+                        PrintItem::NextZone
+                    } else if matches!(
+                        items.last(),
+                        Some(PrintItem::Expression(_) | PrintItem::Tab(_) | PrintItem::Spc(_))
+                    ) {
+                        // Items must be separated, so whatever is here is
+                        // unexpected.
+                        break;
+                    } else if self.peek_keyword(TAB) {
+                        self.skip_token(TAB)?;
+                        PrintItem::Tab(self.get_single_arg(TAB)?)
+                    } else if self.peek_keyword(SPC) {
+                        self.skip_token(SPC)?;
+                        PrintItem::Spc(self.get_single_arg(SPC)?)
+                    } else {
+                        PrintItem::Expression(self.get_expression()?)
+                    };
+
+                    items.push(item);
                 }
 
-                Ok(Statement::Print(args))
+                Ok(Statement::Print(items))
             }
 
             LET => {
-                let var_name = self.get_char()?;
-                self.skip_token(String::from("="));
-                let exp = Ok(self.get_expression()?);
+                let target = self.get_target()?;
+                self.skip_token("=")?;
+                let exp = self.get_expression()?;
+
+                Ok(Statement::Let(target, exp))
+            }
+
+            DIM => {
+                let mut arrays: Vec<(String, Vec<Expression>)> = Vec::new();
 
-                Ok(Statement::Let(var_name, exp?))
+                loop {
+                    let name = self.get_var_name()?;
+                    let bounds = self.get_args()?;
+
+                    if bounds.is_empty() {
+                        return Err(BasicError::syntax("Expected array dimensions"));
+                    }
+
+                    arrays.push((name, bounds));
+
+                    // More?
+                    if self.ch() != ',' {
+                        break;
+                    }
+                    self.skip_token(",")?;
+                }
+
+                Ok(Statement::Dim(arrays))
             }
 
             IF => {
-                let l_exp = self.get_expression()?;
-                let relop = self.get_relop()?;
-                let r_exp = self.get_expression()?;
-                self.skip_token(String::from(THEN));
+                let condition = self.get_expression()?;
+                self.skip_token(THEN)?;
+
+                // The consequent statements follow in the rest of the line.
+                Ok(Statement::If(condition))
+            }
+
+            ELSE => Ok(Statement::Else),
 
-                // Recursive call to build the consequent statement.
-                let sub = self.build_statement()?;
+            ELSEIF => {
+                let condition = self.get_expression()?;
+                self.skip_token(THEN)?;
 
-                Ok(Statement::If(
-                    Condition::Boolean(l_exp, relop, r_exp),
-                    Box::new(sub),
-                ))
+                Ok(Statement::ElseIf(condition))
             }
 
-            GOTO => Ok(Statement::Goto(self.get_integer()?)),
+            DEF => {
+                self.skip_ws();
+                let name = self.get_identifier()?;
+                if !is_user_function(&name) {
+                    return Err(BasicError::syntax("Function names must start with fn"));
+                }
 
-            INPUT => Ok(Statement::Input(self.get_char()?)),
+                let params = self.get_params()?;
+                self.skip_token("=")?;
+                let body = self.get_expression()?;
+
+                Ok(Statement::Def(name, params, body))
+            }
 
-            GOSUB => Ok(Statement::Gosub(self.get_integer()?)),
+            DATA => {
+                let mut values: Vec<Value> = Vec::new();
+
+                loop {
+                    values.push(self.get_literal()?);
+
+                    // More?
+                    if self.ch() != ',' {
+                        break;
+                    }
+                    self.skip_token(",")?;
+                }
+
+                Ok(Statement::Data(values))
+            }
+
+            READ => {
+                let mut targets = vec![self.get_target()?];
+                self.skip_ws();
+
+                while self.ch() == ',' {
+                    self.skip_token(",")?;
+                    targets.push(self.get_target()?);
+                    self.skip_ws();
+                }
+
+                Ok(Statement::Read(targets))
+            }
+
+            RESTORE => match self.at_end_of_statement() {
+                true => Ok(Statement::Restore(None)),
+                false => Ok(Statement::Restore(Some(self.get_integer()?))),
+            },
+
+            // Choose how large integers can get: `integer 32`, `integer 64`
+            // or `integer big`.
+            INTEGER => {
+                self.skip_ws();
+                let start = self.idx;
+                while self.is_alpha() || self.is_digit() {
+                    self.next();
+                }
+                let name = self.line[start..self.idx].to_string();
+                self.skip_ws();
+
+                match IntegerMode::from_name(&name) {
+                    Some(mode) => Ok(Statement::Integer(mode)),
+                    None => Err(BasicError::syntax("Expected 32, 64 or big after integer")),
+                }
+            }
+
+            RANDOMIZE => match self.at_end_of_statement() {
+                true => Ok(Statement::Randomize(None)),
+                false => Ok(Statement::Randomize(Some(self.get_expression()?))),
+            },
+
+            GOTO => Ok(Statement::Goto(self.get_expression()?)),
+
+            INPUT => {
+                self.skip_ws();
+
+                // An optional prompt comes first, followed by a semicolon.
+                let prompt = match self.ch() == '"' {
+                    true => {
+                        let text = self.get_string()?.to_string();
+                        self.skip_token(";")?;
+                        Some(text)
+                    }
+                    false => None,
+                };
+
+                let mut targets = vec![self.get_target()?];
+                self.skip_ws();
+
+                while self.ch() == ',' {
+                    self.skip_token(",")?;
+                    targets.push(self.get_target()?);
+                    self.skip_ws();
+                }
+
+                Ok(Statement::Input(prompt, targets))
+            }
+
+            GOSUB => Ok(Statement::Gosub(self.get_expression()?)),
+
+            // `on error goto` sets the line to jump to when an error
+            // happens.
+            ON if self.peek_keyword(ERROR) => {
+                self.skip_token(ERROR)?;
+                self.skip_token(GOTO)?;
+                Ok(Statement::OnError(self.get_integer()?))
+            }
+
+            ON => {
+                let selector = self.get_expression()?;
+                self.skip_ws();
+
+                let is_gosub = match self.peek_keyword(GOSUB) {
+                    true => {
+                        self.skip_token(GOSUB)?;
+                        true
+                    }
+                    false => {
+                        self.skip_token(GOTO)?;
+                        false
+                    }
+                };
+
+                // Read the list of target lines.
+                let mut targets = vec![self.get_expression()?];
+                self.skip_ws();
+
+                while self.ch() == ',' {
+                    self.skip_token(",")?;
+                    targets.push(self.get_expression()?);
+                    self.skip_ws();
+                }
+
+                match is_gosub {
+                    true => Ok(Statement::OnGosub(selector, targets)),
+                    false => Ok(Statement::OnGoto(selector, targets)),
+                }
+            }
 
             RETURN => Ok(Statement::Return),
 
+            RESUME => {
+                self.skip_ws();
+
+                if self.at_end_of_statement() {
+                    Ok(Statement::Resume(None))
+                } else if self.peek_keyword(NEXT) {
+                    self.skip_token(NEXT)?;
+                    Ok(Statement::ResumeNext)
+                } else {
+                    Ok(Statement::Resume(Some(self.get_integer()?)))
+                }
+            }
+
             FOR => {
-                let var = self.get_char()?;
-                self.skip_token(String::from("="));
+                let var = self.get_var_name()?;
+                if is_string_var(&var) {
+                    return Err(BasicError::syntax("Loop variable must be numeric"));
+                }
+                self.skip_token("=")?;
                 let start_val = self.get_expression()?;
-                self.skip_token(TO.to_string());
+                self.skip_token(TO)?;
                 let end_val = self.get_expression()?;
                 self.skip_ws();
 
-                let step_val = match self.at_end() {
+                let step_val = match self.at_end_of_statement() {
                     true => None,
                     false => {
-                        self.skip_token(STEP.to_string());
-                        self.skip_ws();
+                        self.skip_token(STEP)?;
                         Some(self.get_expression()?)
                     }
                 };
@@ -507,19 +1061,97 @@ impl SourceReader {
                 Ok(Statement::For(var, start_val, end_val, step_val))
             }
 
-            NEXT => Ok(Statement::Next),
+            // A next can name the loop variables it ends, e.g. `next j, i`.
+            NEXT => {
+                let mut vars = Vec::new();
+                self.skip_ws();
+
+                if !self.at_end_of_statement() {
+                    vars.push(self.get_var_name()?);
+                    self.skip_ws();
+
+                    while self.ch() == ',' {
+                        self.skip_token(",")?;
+                        vars.push(self.get_var_name()?);
+                        self.skip_ws();
+                    }
+                }
+
+                Ok(Statement::Next(vars))
+            }
+
+            WHILE => Ok(Statement::While(self.get_expression()?)),
+
+            WEND => Ok(Statement::Wend),
+
+            DO => Ok(Statement::Do),
+
+            LOOP => {
+                self.skip_ws();
+
+                if self.peek_keyword(UNTIL) {
+                    self.skip_token(UNTIL)?;
+                    Ok(Statement::LoopUntil(self.get_expression()?))
+                } else if self.peek_keyword(WHILE) {
+                    self.skip_token(WHILE)?;
+                    Ok(Statement::LoopWhile(self.get_expression()?))
+                } else {
+                    Err(BasicError::syntax("Expected until or while after loop"))
+                }
+            }
 
             LIST => Ok(Statement::List),
 
             RUN => Ok(Statement::Run),
 
+            STOP => Ok(Statement::Stop),
+
+            CONT => Ok(Statement::Cont),
+
             LOAD => Ok(Statement::Load(self.get_string()?.to_string())),
 
             SAVE => Ok(Statement::Save(self.get_string()?.to_string())),
 
             CLEAR => Ok(Statement::Clear),
 
-            END => Ok(Statement::End),
+            SUB => {
+                let name = self.get_var_name()?;
+                Ok(Statement::Sub(name, self.get_params()?))
+            }
+
+            FUNCTION => {
+                let name = self.get_var_name()?;
+                Ok(Statement::Function(name, self.get_params()?))
+            }
+
+            CALL => {
+                let name = self.get_var_name()?;
+                self.skip_ws();
+
+                let args = match self.ch() == '(' {
+                    true => self.get_args()?,
+                    false => Vec::new(),
+                };
+
+                Ok(Statement::Call(name, args))
+            }
+
+            END => {
+                self.skip_ws();
+
+                if self.peek_keyword(IF) {
+                    self.skip_token(IF)?;
+                    Ok(Statement::EndIf)
+                } else if self.peek_keyword(SUB) {
+                    self.skip_token(SUB)?;
+                    Ok(Statement::EndSub)
+                } else if self.peek_keyword(FUNCTION) {
+                    self.skip_token(FUNCTION)?;
+                    Ok(Statement::EndFunction)
+                } else {
+                    Ok(Statement::End)
+                }
+            }
 
             _ => {
                 match keyword.len() {
@@ -528,40 +1160,136 @@ impl SourceReader {
                         if self.at_end() {
                             return Ok(Statement::Empty);
                         }
-                        return Err(BasicError::SyntaxError(String::from("Unknown keyword")));
-                    }
-                    1 => {
-                        // Is it a let statement without let?
-                        let var_name = keyword.chars().nth(0).expect("Error getting keyword");
-                        self.skip_token(String::from("="));
-                        let exp = self.get_expression()?;
-
-                        return Ok(Statement::Let(var_name, exp));
+                        return Err(BasicError::syntax("Unknown keyword"));
                     }
                     _ => {
+                        // Is it a let statement without let? If so, go back
+                        // and read the keyword as a variable or array element.
+                        self.idx = start_at;
+                        let target = self.get_target()?;
+
                         // Otherwise, whatever is there isn't a recognisable keyword
-                        return Err(BasicError::SyntaxError(String::from("Unknown keyword")));
+                        if self.ch() != '=' {
+                            return Err(BasicError::syntax("Unknown keyword"));
+                        }
+
+                        self.skip_token("=")?;
+                        let exp = self.get_expression()?;
+
+                        Ok(Statement::Let(target, exp))
                     }
                 }
             }
         };
 
-        // Post compile checks
-        if !self.at_end() {
-            return Err(BasicError::SyntaxError(String::from("Unexpected token")));
+        // Post compile checks. The statement must end the line or be followed
+        // by another, unless it's an if (which is followed by its consequent).
+        if !self.at_end_of_statement()
+            && !matches!(statement, Ok(Statement::If(_) | Statement::Else))
+        {
+            return Err(BasicError::syntax("Unexpected token"));
         }
 
-        if !self.exp_depth == 0 {
-            return Err(BasicError::SyntaxError(String::from("Invalid expression")));
+        if self.exp_depth != 0 {
+            return Err(BasicError::syntax("Invalid expression"));
         }
 
-        return statement;
+        statement
+    }
+
+    /// Compile the current line into a list of Statements. Statements are
+    /// separated by colons, except that the consequent of an if follows its
+    /// `then` directly. An error marks where in the line reading stopped.
+    pub fn build_line(&mut self) -> Result<Vec<Statement>, BasicError> {
+        self.spans.clear();
+
+        self.read_statements().map_err(|e| {
+            let column = self.line.chars().take(self.idx).count() + 1;
+            e.at(Span { column, width: 1 }).in_source(&self.line)
+        })
+    }
+
+    /// Read the statements in the current line, for `build_line`.
+    fn read_statements(&mut self) -> Result<Vec<Statement>, BasicError> {
+        let mut statements: Vec<Statement> = Vec::new();
+
+        let mut ifs = 0;
+        let mut elses = 0;
+
+        loop {
+            self.skip_ws();
+            let start_at = self.idx;
+            let mut statement = self.build_statement()?;
+
+            // Statements directly follow a then or else, without a colon.
+            let follows_directly = match statement {
+                Statement::If(_) => {
+                    ifs += 1;
+                    true
+                }
+                // An else outside of any single-line if belongs to a block if.
+                Statement::Else if ifs == 0 => {
+                    statement = Statement::BlockElse;
+                    false
+                }
+                Statement::Else => {
+                    elses += 1;
+                    if elses > ifs {
+                        return Err(BasicError::syntax("Else without if"));
+                    }
+                    true
+                }
+                Statement::ElseIf(_) | Statement::EndIf if ifs > 0 => {
+                    return Err(BasicError::syntax(
+                        "Block if statements can't be used in a single-line if",
+                    ));
+                }
+                _ => false,
+            };
+
+            self.skip_ws();
+
+            // An if with nothing after its then starts a block if, which
+            // runs until the matching end if.
+            if let Statement::If(condition) = statement {
+                statement = match self.at_end() && ifs == 1 {
+                    true => {
+                        ifs -= 1;
+                        Statement::BlockIf(condition)
+                    }
+                    false => Statement::If(condition),
+                };
+            }
+
+            let is_block_if = matches!(statement, Statement::BlockIf(_));
+
+            if !matches!(statement, Statement::Empty) {
+                statements.push(statement);
+                self.spans.push(self.span_from(start_at));
+            }
+
+            if self.at_end() {
+                if follows_directly && !is_block_if {
+                    return Err(BasicError::syntax(
+                        "Expected a statement after then or else",
+                    ));
+                }
+                break;
+            }
+
+            if !follows_directly && !self.peek_keyword(ELSE) {
+                self.skip_token(":")?;
+            }
+        }
+
+        Ok(statements)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::statement::format_statements;
 
     fn new_reader(src: &str) -> SourceReader {
         SourceReader::new(src.to_string())
@@ -590,10 +1318,10 @@ mod test {
             Ok(s) => {
                 if let Statement::Print(exps) = s {
                     assert_eq!(exps.len(), 1);
-                    match exps.get(0) {
+                    match exps.first() {
                         None => panic!("Print argument empty"),
                         Some(e) => {
-                            if let Expression::Numeric(i) = e {
+                            if let PrintItem::Expression(Expression::Numeric(i)) = e {
                                 assert!(i.is_int() && *i == Number::Integer(2))
                             }
                         }
@@ -610,10 +1338,10 @@ mod test {
         match reader.build_statement() {
             Err(e) => panic!("{}", e),
             Ok(s) => {
-                if let Statement::Let(var, exp) = s {
-                    assert_eq!(var, 'x');
+                if let Statement::Let(Target::Variable(var), exp) = s {
+                    assert_eq!(var, "x");
 
-                    match exp {
+                    match exp.unspanned() {
                         Expression::Operator(op, exp1, exp2) => {
                             if let ArithOp::Add = op {
                                 assert!(exp1.is_some());
@@ -631,29 +1359,32 @@ mod test {
     fn builds_if() {
         let mut reader = new_reader("if x > 1 then print 99");
 
-        match reader.build_statement() {
+        match reader.build_line() {
             Err(e) => panic!("{}", e),
-            Ok(s) => {
-                if let Statement::If(cond, stmt) = s {
-                    match cond {
-                        Condition::Boolean(lexp, relop, rexp) => {
-                            if let Expression::Numeric(Number::Integer(1)) = rexp
-                                && let Relop::GT = relop
-                                && let Expression::Variable('x') = lexp
-                            {
-                                // OK
-                            } else {
-                                panic!("Error in condition");
-                            }
+            Ok(stmts) => {
+                assert_eq!(stmts.len(), 2);
+
+                match &stmts[0] {
+                    Statement::If(exp) => {
+                        if let Expression::Relation(relop, lexp, rexp) = exp.unspanned()
+                            && let Expression::Numeric(Number::Integer(1)) = rexp.unspanned()
+                            && let Relop::GT = relop
+                            && let Expression::Variable(name) = lexp.unspanned()
+                            && name == "x"
+                        {
+                            // OK
+                        } else {
+                            panic!("Error in condition");
                         }
                     }
+                    _ => panic!("Wrong statement"),
+                }
 
-                    match *stmt {
-                        Statement::Print(exps) => {
-                            assert_eq!(exps.len(), 1)
-                        }
-                        _ => panic!("Error in statement"),
+                match &stmts[1] {
+                    Statement::Print(exps) => {
+                        assert_eq!(exps.len(), 1)
                     }
+                    _ => panic!("Error in statement"),
                 }
             }
         }
@@ -667,7 +1398,7 @@ mod test {
             Err(e) => panic!("{}", e),
             Ok(s) => match s {
                 Statement::For(var, start, end, step) => {
-                    assert_eq!(var, 'i');
+                    assert_eq!(var, "i");
                     if let Expression::Numeric(Number::Integer(1)) = start {
                     } else {
                         panic!("Error in variable");
@@ -690,8 +1421,10 @@ mod test {
         match reader.build_statement() {
             Err(e) => panic!("{}", e),
             Ok(s) => {
-                if let Statement::Goto(num) = s {
-                    assert_eq!(num, 9999)
+                if let Statement::Goto(Expression::Numeric(num)) = s {
+                    assert_eq!(num, Number::Integer(9999))
+                } else {
+                    panic!("Wrong statement");
                 }
             }
         }
@@ -710,4 +1443,394 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn builds_dim() {
+        let mut reader = new_reader("dim a(10), b$(2, 3)");
+
+        match reader.build_statement() {
+            Err(e) => panic!("{}", e),
+            Ok(s) => match s {
+                Statement::Dim(arrays) => {
+                    assert_eq!(arrays.len(), 2);
+                    assert_eq!(arrays[0].0, "a");
+                    assert_eq!(arrays[0].1.len(), 1);
+                    assert_eq!(arrays[1].0, "b$");
+                    assert_eq!(arrays[1].1.len(), 2);
+                }
+                _ => panic!("Wrong statement"),
+            },
+        }
+    }
+
+    #[test]
+    fn builds_element_assignment() {
+        let mut reader = new_reader("a(i, 2) = b(i) + int(3.5)");
+
+        match reader.build_statement() {
+            Err(e) => panic!("{}", e),
+            Ok(s) => match s {
+                Statement::Let(Target::Element(name, indices), exp) => {
+                    assert_eq!(name, "a");
+                    assert_eq!(indices.len(), 2);
+
+                    if let Expression::Operator(_, Some(l_exp), Some(r_exp)) = exp.unspanned()
+                        && let Expression::Element(_, _) = l_exp.unspanned()
+                        && let Expression::Function(_, _) = r_exp.unspanned()
+                    {
+                        // OK
+                    } else {
+                        panic!("Error in expression");
+                    }
+                }
+                _ => panic!("Wrong statement"),
+            },
+        }
+    }
+
+    #[test]
+    fn builds_long_variable_names() {
+        let mut reader = new_reader("total2 = count * 2");
+
+        match reader.build_statement() {
+            Err(e) => panic!("{}", e),
+            Ok(s) => match s {
+                Statement::Let(Target::Variable(name), exp) => {
+                    assert_eq!(name, "total2");
+                    assert_eq!(exp.to_string(), "count*2");
+                }
+                _ => panic!("Wrong statement"),
+            },
+        }
+    }
+
+    #[test]
+    fn builds_names_starting_with_keywords() {
+        for name in ["do2", "rem1", "end1", "for1"] {
+            match new_reader(&format!("{} = 3", name)).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), format!("let {}=3", name)),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        match new_reader("print rem1 + end1").build_line() {
+            Ok(stmts) => assert_eq!(format_statements(&stmts), "print rem1+end1"),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_variable_names() {
+        assert!(new_reader("let to = 1").build_statement().is_err());
+        assert!(new_reader("for int = 1 to 10").build_statement().is_err());
+        assert!(new_reader("input rnd").build_statement().is_err());
+        assert!(new_reader("print then").build_statement().is_err());
+    }
+
+    #[test]
+    fn builds_multiple_statements() {
+        let mut reader = new_reader("a = 1 : b$ = \"x:y\" : if a = 1 then print a : print b$");
+
+        match reader.build_line() {
+            Err(e) => panic!("{}", e),
+            Ok(stmts) => {
+                assert_eq!(stmts.len(), 5);
+                assert!(matches!(stmts[2], Statement::If(_)));
+                assert_eq!(
+                    format_statements(&stmts),
+                    "let a=1 : let b$=\"x:y\" : if a=1 then print a : print b$"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_separators() {
+        assert!(new_reader("a = 1 b = 2").build_line().is_err());
+        assert!(
+            new_reader("if a = 1 then if b = 1 then")
+                .build_line()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn displays_boolean_expressions() {
+        let sources = [
+            "if a>1 and b<2 or not c=3 then print 1 else print 2",
+            "if (a or b) and c then print 1",
+            "if not (a and b) then print a=(b=c)",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_else_without_if() {
+        assert!(new_reader("print 1 else print 2").build_line().is_err());
+        assert!(new_reader("if a then print 1 else").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_computed_jumps() {
+        let sources = [
+            "goto n*10+100",
+            "gosub 1000+x",
+            "on x goto 100, 200, 300",
+            "on x+1 gosub 100, a*10",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("on x print 100").build_line().is_err());
+        assert!(new_reader("on x goto").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_def() {
+        let sources = [
+            "def fna(x)=x*x+1",
+            "def fnmax(a, b)=a*(a>=b)+b*(b>a)",
+            "def fnpi()=3.14159",
+            "def fnname$(a$)=a$+\"!\"",
+            "print fna(2)+fnmax(1, fna(3))",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("def square(x) = x * x").build_line().is_err());
+        assert!(new_reader("def fna(x, x) = x").build_line().is_err());
+        assert!(new_reader("fna = 1").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_procedures() {
+        let sources = [
+            "sub greet(name$, times)",
+            "end sub",
+            "function fact(n)",
+            "end function",
+            "function answer",
+            "call greet(\"Bob\", 2)",
+            "call tidy",
+            "print fact(5)+answer",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("sub print(x)").build_line().is_err());
+        assert!(new_reader("function f(a, a)").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_data() {
+        let sources = [
+            "data 1, -2, 3.5, \"four, five\", +6",
+            "read a, b$, c(1, 2)",
+            "restore",
+            "restore 100",
+        ];
+        let expected = [
+            "data 1, -2, 3.5, \"four, five\", 6",
+            "read a, b$, c(1, 2)",
+            "restore",
+            "restore 100",
+        ];
+
+        for (src, output) in sources.iter().zip(expected) {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), output),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("data 1, x").build_line().is_err());
+        assert!(new_reader("data").build_line().is_err());
+        assert!(new_reader("read").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_input() {
+        let sources = ["input a", "input a, b$, c(2)", "input \"Your age\"; age"];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("input \"Your age\" age").build_line().is_err());
+        assert!(new_reader("input \"Your age\";").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_print_separators() {
+        let sources = [
+            "print",
+            "print a; b$, c",
+            "print \"total:\"; tab(20); t;",
+            "print spc(3+x), \"x\",",
+            "print ,; a",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("print a b").build_line().is_err());
+        assert!(new_reader("print tab(1, 2)").build_line().is_err());
+        assert!(new_reader("x = tab(1)").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_print_using() {
+        let sources = [
+            "print using \"###.##\"; x",
+            "print using f$; a, b$;",
+            "print using \"#,###\"; n*1000",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("print using \"##\"").build_line().is_err());
+        assert!(new_reader("print using \"##\";").build_line().is_err());
+        assert!(new_reader("using = 1").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_math_functions() {
+        let sources = [
+            "let a=2*pi*r",
+            "print sqr(abs(x)), round(y, 2), max(a, b, c)",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("sin = 1").build_line().is_err());
+    }
+
+    #[test]
+    fn displays_arithmetic_expressions() {
+        let sources = [
+            "let x=2^3^2",
+            "let x=(2^3)^2",
+            "let x=-2^2",
+            "let x=(-2)^2",
+            "let x=2^-1",
+            "let x=-(a+b)*-c",
+            "let x=a mod b\\c",
+            "let x=(a mod b)\\c",
+            "let x=a-(b-c)",
+            "let x=a/(b*c)",
+            "let x=-a^-b",
+        ];
+
+        for src in sources.iter() {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), *src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert_eq!(
+            format_statements(&new_reader("x = +a").build_line().unwrap()),
+            "let x=a"
+        );
+        assert!(new_reader("x = a mod").build_line().is_err());
+        assert!(new_reader("mod = 1").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_integer_mode() {
+        for src in ["integer 32", "integer 64", "integer big"] {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("integer").build_line().is_err());
+        assert!(new_reader("integer 16").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_randomize() {
+        for src in ["randomize", "randomize 42", "randomize timer+1"] {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert_eq!(
+            format_statements(&new_reader("print rnd(6) + 1").build_line().unwrap()),
+            "print rnd(6)+1"
+        );
+    }
+
+    #[test]
+    fn builds_next_with_variables() {
+        for src in ["next", "next i", "next j, i", "for i=1 to 3 : next i"] {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("next i,").build_line().is_err());
+        assert!(new_reader("next 1").build_line().is_err());
+    }
+
+    #[test]
+    fn builds_error_handling() {
+        for src in [
+            "on error goto 100",
+            "resume",
+            "resume next",
+            "resume 200",
+            "print err; erl",
+        ] {
+            match new_reader(src).build_line() {
+                Ok(stmts) => assert_eq!(format_statements(&stmts), src),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(new_reader("on error 100").build_line().is_err());
+        assert!(new_reader("error = 1").build_line().is_err());
+    }
 }
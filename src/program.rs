@@ -1,20 +1,63 @@
-use crate::statement::Statement;
+use std::collections::HashMap;
+
+use crate::{
+    errors::{BasicError, Span},
+    statement::Statement,
+};
+
+/// A position in a program: the index of a line, and the index of a
+/// statement within that line. Used as the Executor's program counter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub stmt: usize,
+}
+
+impl Position {
+    /// The position of the first statement of the line at the given index.
+    pub fn start_of(line: usize) -> Position {
+        Position { line, stmt: 0 }
+    }
+
+    /// The position of the following statement in the same line.
+    pub fn next_statement(&self) -> Position {
+        Position {
+            line: self.line,
+            stmt: self.stmt + 1,
+        }
+    }
+
+    /// The position of the first statement of the following line.
+    pub fn next_line(&self) -> Position {
+        Position::start_of(self.line + 1)
+    }
+}
 
 /// Find the index of the line with the given line number. The index can
 /// be used as a value for the Executor's program counter.
-pub fn find_line(program: &Vec<(i32, Statement)>, line_num: i32) -> Option<usize> {
+pub fn find_line(program: &[(i32, Vec<Statement>)], line_num: i32) -> Option<usize> {
     for (pc, line) in program.iter().enumerate() {
         if line.0 == line_num {
             return Some(pc);
         }
     }
 
-    return None;
+    None
 }
 
-pub fn update_program(program: &mut Vec<(i32, Statement)>, new_line: (i32, Statement)) {
+/// Add a line to the program, replacing any existing line with the same
+/// number. A line with no statements removes the existing line.
+pub fn update_program(program: &mut Vec<(i32, Vec<Statement>)>, new_line: (i32, Vec<Statement>)) {
+    // An empty line just removes the old one (if any).
+    if new_line.1.is_empty() {
+        if let Some(idx) = find_line(program, new_line.0) {
+            program.remove(idx);
+        }
+        return;
+    }
+
     // The program is empty, so insert immediately.
-    if program.len() == 0 {
+    if program.is_empty() {
         program.push(new_line);
         return;
     }
@@ -22,16 +65,8 @@ pub fn update_program(program: &mut Vec<(i32, Statement)>, new_line: (i32, State
     // Look for a place to add it, starting from the end of the program...
     for idx in (0..program.len()).rev() {
         if new_line.0 == program[idx].0 {
-            // If the line number already exists, we must check if we're
-            // replacing with a new line or just removing the old one.
-            match new_line.1 {
-                Statement::Empty => {
-                    program.remove(idx);
-                }
-                _ => {
-                    program[idx] = (new_line.0, new_line.1);
-                }
-            }
+            // If the line number already exists, replace it.
+            program[idx] = new_line;
 
             return;
         }
@@ -49,14 +84,339 @@ pub fn update_program(program: &mut Vec<(i32, Statement)>, new_line: (i32, State
     program.insert(0, (new_line.0, new_line.1));
 }
 
+/// A program as it's entered at the prompt or loaded: its lines, along
+/// with the text of each as it was typed, so that errors can show it.
+#[derive(Default)]
+pub struct Program {
+    pub lines: Vec<(i32, Vec<Statement>)>,
+
+    /// The text of each line, and where each of its statements is in it,
+    /// by line number.
+    sources: HashMap<i32, (String, Vec<Span>)>,
+}
+
+impl Program {
+    /// Add a line to the program along with its text, replacing any
+    /// existing line with the same number. A line with no statements
+    /// removes the existing line and its text.
+    pub fn update(&mut self, line: (i32, Vec<Statement>), source: &str, spans: &[Span]) {
+        match line.1.is_empty() {
+            true => self.sources.remove(&line.0),
+            false => self
+                .sources
+                .insert(line.0, (source.trim_end().to_string(), spans.to_vec())),
+        };
+
+        update_program(&mut self.lines, line);
+    }
+
+    /// Show the text of the program line an error happened in, marking the
+    /// statement it happened in unless a part of it is already marked.
+    pub fn with_source(&self, error: BasicError) -> BasicError {
+        let Some((source, spans)) = error.line.and_then(|n| self.sources.get(&n)) else {
+            return error;
+        };
+
+        let error = match error.statement.and_then(|stmt| spans.get(stmt)) {
+            Some(span) => error.at(*span),
+            None => error,
+        };
+
+        error.in_source(source)
+    }
+}
+
+/// Find the `wend` that matches the `while` at the given position,
+/// skipping over any nested while loops.
+pub fn find_wend(program: &[(i32, Vec<Statement>)], while_pos: Position) -> Option<Position> {
+    let mut depth = 0;
+    let mut pos = while_pos.next_statement();
+
+    while pos.line < program.len() {
+        match program[pos.line].1.get(pos.stmt) {
+            None => {
+                pos = pos.next_line();
+                continue;
+            }
+            Some(Statement::While(_)) => depth += 1,
+            Some(Statement::Wend) if depth == 0 => return Some(pos),
+            Some(Statement::Wend) => depth -= 1,
+            Some(_) => (),
+        }
+
+        pos = pos.next_statement();
+    }
+
+    None
+}
+
+/// Find the `loop until` or `loop while` that matches the `do` at the given
+/// position, skipping over any nested do loops.
+pub fn find_loop(program: &[(i32, Vec<Statement>)], do_pos: Position) -> Option<Position> {
+    let mut depth = 0;
+    let mut pos = do_pos.next_statement();
+
+    while pos.line < program.len() {
+        match program[pos.line].1.get(pos.stmt) {
+            None => {
+                pos = pos.next_line();
+                continue;
+            }
+            Some(Statement::Do) => depth += 1,
+            Some(Statement::LoopUntil(_) | Statement::LoopWhile(_)) if depth == 0 => {
+                return Some(pos);
+            }
+            Some(Statement::LoopUntil(_) | Statement::LoopWhile(_)) => depth -= 1,
+            Some(_) => (),
+        }
+
+        pos = pos.next_statement();
+    }
+
+    None
+}
+
+/// Find the `next` that ends the for loop with the given variable, which
+/// starts at the given position. Nested for loops are skipped over, along
+/// with the `next` that ends each of them.
+pub fn find_next(
+    program: &[(i32, Vec<Statement>)],
+    for_pos: Position,
+    var: &str,
+) -> Option<Position> {
+    let mut depth = 0;
+    let mut pos = for_pos.next_statement();
+
+    while pos.line < program.len() {
+        match program[pos.line].1.get(pos.stmt) {
+            None => {
+                pos = pos.next_line();
+                continue;
+            }
+            Some(Statement::For(..)) => depth += 1,
+            Some(Statement::Next(names)) if names.is_empty() => match depth {
+                0 => return Some(pos),
+                _ => depth -= 1,
+            },
+            // Each name in a next ends one loop.
+            Some(Statement::Next(names)) => {
+                for name in names {
+                    match depth {
+                        0 if name == var => return Some(pos),
+                        0 => (),
+                        _ => depth -= 1,
+                    }
+                }
+            }
+            Some(_) => (),
+        }
+
+        pos = pos.next_statement();
+    }
+
+    None
+}
+
+/// A procedure defined with `sub` or `function`, with a copy of the lines
+/// between its start and end.
+pub struct Procedure {
+    pub params: Vec<String>,
+    pub is_function: bool,
+    pub lines: Vec<(i32, Vec<Statement>)>,
+
+    /// The matching parts of each block if in the procedure's lines.
+    pub blocks: HashMap<Position, Position>,
+}
+
+impl Procedure {
+    /// Create a procedure from the statements between the given start and
+    /// end positions of a program (which must already be matched).
+    pub fn new(
+        program: &[(i32, Vec<Statement>)],
+        start: Position,
+        end: Position,
+        params: &[String],
+        is_function: bool,
+    ) -> Result<Procedure, BasicError> {
+        let mut lines: Vec<(i32, Vec<Statement>)> = Vec::new();
+
+        for (line_idx, (line_num, statements)) in program
+            .iter()
+            .enumerate()
+            .take(end.line + 1)
+            .skip(start.line)
+        {
+            let first = match line_idx == start.line {
+                true => start.stmt + 1,
+                false => 0,
+            };
+            let last = match line_idx == end.line {
+                true => end.stmt,
+                false => statements.len(),
+            };
+
+            // Statements before the start are left empty rather than out,
+            // so that each statement keeps its index in the line, which is
+            // used to mark it when there's an error.
+            if first < last {
+                let mut line = vec![Statement::Empty; first];
+                line.extend_from_slice(&statements[first..last]);
+                lines.push((*line_num, line));
+            }
+        }
+
+        let blocks = match_blocks(&lines)?;
+
+        Ok(Procedure {
+            params: params.to_vec(),
+            is_function,
+            lines,
+            blocks,
+        })
+    }
+}
+
+/// Match up the parts of every block if in the program. Each `if`,
+/// `elseif` and `else` of a block is mapped to the position of the next
+/// part of the same block, ending with its `end if`. Each `sub` or
+/// `function` is mapped to its `end sub` or `end function`, and each
+/// `for`, `while` or `do` to the end of its loop, if it has one.
+///
+/// # Arguments
+/// * `program` - The program to check
+///
+/// # Returns
+/// * The map of positions, or a syntax error naming the offending line
+pub fn match_blocks(
+    program: &[(i32, Vec<Statement>)],
+) -> Result<HashMap<Position, Position>, BasicError> {
+    let mut blocks: HashMap<Position, Position> = HashMap::new();
+
+    // Open blocks, as the position of the last part seen so far and whether
+    // the else part has been reached.
+    let mut open: Vec<(Position, bool)> = Vec::new();
+
+    // The procedure being defined, if any, as its position, whether it's a
+    // function, and how many blocks were open when it started. Blocks
+    // can't cross the start or end of a procedure.
+    let mut procedure: Option<(Position, bool, usize)> = None;
+
+    let block_error = |msg: &str, line_num: i32| BasicError::syntax(msg).in_line(line_num);
+
+    for (line_idx, (line_num, statements)) in program.iter().enumerate() {
+        for (stmt_idx, statement) in statements.iter().enumerate() {
+            let pos = Position {
+                line: line_idx,
+                stmt: stmt_idx,
+            };
+
+            let base = procedure.map_or(0, |p| p.2);
+
+            match statement {
+                Statement::BlockIf(_) => open.push((pos, false)),
+
+                Statement::ElseIf(_) | Statement::BlockElse => {
+                    let is_else = matches!(statement, Statement::BlockElse);
+
+                    match open.get_mut(base..).and_then(|o| o.last_mut()) {
+                        None if is_else => return Err(block_error("Else without if", *line_num)),
+                        None => return Err(block_error("Elseif without if", *line_num)),
+                        Some((_, true)) if is_else => {
+                            return Err(block_error("Else after else", *line_num));
+                        }
+                        Some((_, true)) => {
+                            return Err(block_error("Elseif after else", *line_num));
+                        }
+                        Some((last, seen_else)) => {
+                            blocks.insert(*last, pos);
+                            *last = pos;
+                            *seen_else = is_else;
+                        }
+                    }
+                }
+
+                Statement::EndIf if open.len() <= base => {
+                    return Err(block_error("End if without if", *line_num));
+                }
+                Statement::EndIf => {
+                    if let Some((last, _)) = open.pop() {
+                        blocks.insert(last, pos);
+                    }
+                }
+
+                // Note where each loop ends, so that a jump can tell
+                // whether it leaves the loop.
+                Statement::For(var, _, _, _) => {
+                    if let Some(end) = find_next(program, pos, var) {
+                        blocks.insert(pos, end);
+                    }
+                }
+                Statement::While(_) => {
+                    if let Some(end) = find_wend(program, pos) {
+                        blocks.insert(pos, end);
+                    }
+                }
+                Statement::Do => {
+                    if let Some(end) = find_loop(program, pos) {
+                        blocks.insert(pos, end);
+                    }
+                }
+
+                Statement::Sub(_, _) | Statement::Function(_, _) => {
+                    if procedure.is_some() {
+                        return Err(block_error("Sub or function inside another", *line_num));
+                    }
+                    let is_function = matches!(statement, Statement::Function(_, _));
+                    procedure = Some((pos, is_function, open.len()));
+                }
+
+                Statement::EndSub | Statement::EndFunction => {
+                    let is_function = matches!(statement, Statement::EndFunction);
+
+                    match procedure {
+                        Some((start, f, base)) if f == is_function => {
+                            if let Some((last, _)) = open.get(base..).and_then(|o| o.last()) {
+                                return Err(block_error("If without end if", program[last.line].0));
+                            }
+                            blocks.insert(start, pos);
+                            procedure = None;
+                        }
+                        _ if is_function => {
+                            return Err(block_error("End function without function", *line_num));
+                        }
+                        _ => return Err(block_error("End sub without sub", *line_num)),
+                    }
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    if let Some((start, is_function, _)) = procedure {
+        let msg = match is_function {
+            true => "Function without end function",
+            false => "Sub without end sub",
+        };
+        return Err(block_error(msg, program[start.line].0));
+    }
+
+    // Anything left open has no end if. Report the innermost one.
+    match open.last() {
+        Some((last, _)) => Err(block_error("If without end if", program[last.line].0)),
+        None => Ok(blocks),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{expression::Expression, number::Number};
 
     #[test]
     fn updates_an_empty_program() {
-        let mut program: Vec<(i32, Statement)> = Vec::new();
-        let new_line = (10, Statement::Rem(String::from("Hello")));
+        let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+        let new_line = (10, vec![Statement::Rem(String::from("Hello"))]);
 
         update_program(&mut program, new_line);
 
@@ -65,9 +425,9 @@ mod tests {
 
     #[test]
     fn updates_with_one() {
-        let mut program: Vec<(i32, Statement)> = Vec::new();
-        let new_line_1 = (10, Statement::Rem(String::from("Hello")));
-        let new_line_2 = (20, Statement::Rem(String::from("World")));
+        let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+        let new_line_1 = (10, vec![Statement::Rem(String::from("Hello"))]);
+        let new_line_2 = (20, vec![Statement::Rem(String::from("World"))]);
 
         update_program(&mut program, new_line_1);
         update_program(&mut program, new_line_2);
@@ -79,10 +439,10 @@ mod tests {
 
     #[test]
     fn inserts_with_many() {
-        let mut program: Vec<(i32, Statement)> = Vec::new();
-        let new_line_1 = (10, Statement::Rem(String::from("Hello")));
-        let new_line_2 = (20, Statement::Rem(String::from("World")));
-        let new_line_3 = (15, Statement::Rem(String::from(", ")));
+        let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+        let new_line_1 = (10, vec![Statement::Rem(String::from("Hello"))]);
+        let new_line_2 = (20, vec![Statement::Rem(String::from("World"))]);
+        let new_line_3 = (15, vec![Statement::Rem(String::from(", "))]);
 
         update_program(&mut program, new_line_1);
         update_program(&mut program, new_line_2);
@@ -96,9 +456,12 @@ mod tests {
 
     #[test]
     fn finds_line() {
-        let mut program: Vec<(i32, Statement)> = Vec::new();
-        let new_line_1 = (10, Statement::Rem(String::from("Infinite loop")));
-        let new_line_2 = (20, Statement::Goto(20));
+        let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+        let new_line_1 = (10, vec![Statement::Rem(String::from("Infinite loop"))]);
+        let new_line_2 = (
+            20,
+            vec![Statement::Goto(Expression::Numeric(Number::Integer(20)))],
+        );
 
         update_program(&mut program, new_line_1);
         update_program(&mut program, new_line_2);
@@ -108,4 +471,214 @@ mod tests {
             None => panic!("Error finding line"),
         }
     }
+
+    #[test]
+    fn removes_line() {
+        let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+
+        update_program(
+            &mut program,
+            (10, vec![Statement::Rem(String::from("Hello"))]),
+        );
+        update_program(
+            &mut program,
+            (20, vec![Statement::Rem(String::from("World"))]),
+        );
+        update_program(&mut program, (10, Vec::new()));
+        update_program(&mut program, (30, Vec::new()));
+
+        assert_eq!(program.len(), 1);
+        assert_eq!(program[0].0, 20);
+    }
+
+    #[test]
+    fn matches_blocks() {
+        use crate::parser::SourceReader;
+
+        let build = |src: &[&str]| {
+            let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+            for src_line in src.iter() {
+                let mut reader = SourceReader::new(src_line.to_string());
+                let line_num = reader.get_integer().unwrap();
+                update_program(&mut program, (line_num, reader.build_line().unwrap()));
+            }
+            program
+        };
+
+        let program = build(&[
+            "10 if a then",
+            "20 elseif b then",
+            "30 if c then",
+            "40 print 1 : end if",
+            "50 else",
+            "60 end if",
+        ]);
+
+        match match_blocks(&program) {
+            Err(e) => panic!("{}", e),
+            Ok(blocks) => {
+                assert_eq!(blocks[&Position::start_of(0)], Position::start_of(1));
+                assert_eq!(blocks[&Position::start_of(1)], Position::start_of(4));
+                assert_eq!(blocks[&Position::start_of(4)], Position::start_of(5));
+                assert_eq!(
+                    blocks[&Position::start_of(2)],
+                    Position { line: 3, stmt: 1 }
+                );
+            }
+        }
+
+        // Each loop is matched to its end, if it has one.
+        let program = build(&[
+            "10 for i = 1 to 3 : while a",
+            "20 do : loop until b",
+            "30 wend : next i",
+            "40 do",
+        ]);
+
+        match match_blocks(&program) {
+            Err(e) => panic!("{}", e),
+            Ok(blocks) => {
+                assert_eq!(
+                    blocks[&Position::start_of(0)],
+                    Position { line: 2, stmt: 1 }
+                );
+                assert_eq!(
+                    blocks[&Position { line: 0, stmt: 1 }],
+                    Position::start_of(2)
+                );
+                assert_eq!(
+                    blocks[&Position::start_of(1)],
+                    Position { line: 1, stmt: 1 }
+                );
+                assert!(!blocks.contains_key(&Position::start_of(3)));
+            }
+        }
+
+        let bad_programs: [&[&str]; 4] = [
+            &["10 if a then", "20 print 1"],
+            &["10 print 1", "20 end if"],
+            &["10 if a then", "20 else", "30 elseif b then", "40 end if"],
+            &["10 else"],
+        ];
+
+        for src in bad_programs.iter() {
+            assert!(match_blocks(&build(src)).is_err());
+        }
+    }
+
+    #[test]
+    fn finds_wend() {
+        let program = vec![
+            (
+                10,
+                vec![Statement::While(Expression::Variable(String::from("a")))],
+            ),
+            (
+                20,
+                vec![
+                    Statement::While(Expression::Variable(String::from("b"))),
+                    Statement::Wend,
+                ],
+            ),
+            (30, vec![Statement::Wend]),
+        ];
+
+        assert_eq!(
+            find_wend(&program, Position::start_of(0)),
+            Some(Position::start_of(2))
+        );
+        assert_eq!(
+            find_wend(&program, Position::start_of(1)),
+            Some(Position { line: 1, stmt: 1 })
+        );
+        assert_eq!(find_wend(&program, Position::start_of(2)), None);
+    }
+
+    #[test]
+    fn finds_loop() {
+        let until = || Statement::LoopUntil(Expression::Variable(String::from("a")));
+        let program = vec![
+            (10, vec![Statement::Do]),
+            (20, vec![Statement::Do, until()]),
+            (30, vec![Statement::Wend, until()]),
+        ];
+
+        assert_eq!(
+            find_loop(&program, Position::start_of(0)),
+            Some(Position { line: 2, stmt: 1 })
+        );
+        assert_eq!(
+            find_loop(&program, Position::start_of(1)),
+            Some(Position { line: 1, stmt: 1 })
+        );
+        assert_eq!(find_loop(&program, Position { line: 2, stmt: 1 }), None);
+    }
+
+    #[test]
+    fn finds_next() {
+        let for_loop = |var: &str| {
+            let one = || Expression::Numeric(Number::Integer(1));
+            Statement::For(String::from(var), one(), one(), None)
+        };
+        let next = |vars: &[&str]| Statement::Next(vars.iter().map(|v| v.to_string()).collect());
+
+        let program = vec![
+            (10, vec![for_loop("i"), for_loop("j")]),
+            (20, vec![next(&[]), for_loop("k")]),
+            (30, vec![next(&["k", "i"])]),
+        ];
+
+        assert_eq!(
+            find_next(&program, Position::start_of(0), "i"),
+            Some(Position::start_of(2))
+        );
+        assert_eq!(
+            find_next(&program, Position { line: 0, stmt: 1 }, "j"),
+            Some(Position::start_of(1))
+        );
+        assert_eq!(find_next(&program, Position::start_of(2), "i"), None);
+    }
+
+    #[test]
+    fn matches_procedures() {
+        use crate::parser::SourceReader;
+
+        let build = |src: &[&str]| {
+            let mut program: Vec<(i32, Vec<Statement>)> = Vec::new();
+            for src_line in src.iter() {
+                let mut reader = SourceReader::new(src_line.to_string());
+                let line_num = reader.get_integer().unwrap();
+                update_program(&mut program, (line_num, reader.build_line().unwrap()));
+            }
+            program
+        };
+
+        let program = build(&[
+            "10 print 1",
+            "20 function f(x) : if x then",
+            "30 f = 1",
+            "40 end if : end function",
+        ]);
+
+        let blocks = match_blocks(&program).unwrap();
+        let end = Position { line: 3, stmt: 1 };
+        assert_eq!(blocks[&Position::start_of(1)], end);
+
+        let procedure = Procedure::new(&program, Position::start_of(1), end, &[], true).unwrap();
+        let line_nums: Vec<i32> = procedure.lines.iter().map(|l| l.0).collect();
+        assert_eq!(line_nums, vec![20, 30, 40]);
+        assert_eq!(procedure.lines[2].1.len(), 1);
+
+        let bad_programs: [&[&str]; 5] = [
+            &["10 sub a", "20 sub b", "30 end sub", "40 end sub"],
+            &["10 sub a", "20 end function"],
+            &["10 function a"],
+            &["10 if x then", "20 sub a", "30 end if", "40 end sub"],
+            &["10 sub a", "20 if x then", "30 end sub"],
+        ];
+
+        for src in bad_programs.iter() {
+            assert!(match_blocks(&build(src)).is_err());
+        }
+    }
 }
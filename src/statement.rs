@@ -1,18 +1,23 @@
 use core::fmt;
-use std::{
-    collections::HashMap,
-    fmt::Display,
-    io::{self, Write},
-};
+use std::{fmt::Display, io};
 
 use crate::{
-    errors::BasicError,
-    expression::{Condition, Expression, Number, Relop, eval_expression},
-    function::eval_function,
+    errors::{BasicError, ErrorCode},
+    executor::call_procedure,
+    expression::{
+        Expression, Target, Value, eval_condition, eval_expression, eval_indices, is_string_var,
+        join,
+    },
+    format::format_using,
+    function::define_function,
+    number::{IntegerMode, Number, set_integer_mode},
+    output,
     parser::{
-        CLEAR, END, FOR, GOSUB, GOTO, IF, INPUT, LET, LIST, NEXT, PRINT, REM, RETURN, RUN, STEP,
-        THEN, TO,
+        CALL, CLEAR, CONT, DATA, DEF, DIM, DO, ELSE, ELSEIF, END, ERROR, FOR, FUNCTION, GOSUB,
+        GOTO, IF, INPUT, INTEGER, LET, LIST, LOOP, NEXT, ON, PRINT, RANDOMIZE, READ, REM, RESTORE,
+        RESUME, RETURN, RUN, SPC, STEP, STOP, SUB, TAB, THEN, TO, UNTIL, USING, WEND, WHILE,
     },
+    variables::Variables,
 };
 
 /// Actions that signal back to the executor that it should take action,
@@ -22,11 +27,31 @@ use crate::{
 #[derive(Debug, PartialEq)]
 pub enum ProgramSignal {
     Jump(i32),
+    SkipLine,
+    SkipToElse,
+    // Signal = whether the block if's condition was true
+    BlockIf(bool),
+    SkipToEndIf,
+    EndIf,
     Call(i32),
     Return,
     // Signal = var_name, start_val, end_val, step_val
-    StartLoop(char, i32, i32, Option<i32>),
-    EndLoop,
+    StartLoop(String, Number, Number, Option<Number>),
+    // Signal = the variables named by the next, innermost first
+    EndLoop(Vec<String>),
+    SkipProcedure,
+    EndProcedure,
+    // Signal = whether the while's condition was true
+    StartWhile(bool),
+    EndWhile,
+    StartDo,
+    // Signal = whether to go round the do loop again
+    EndDo(bool),
+    // Signal = the line to resume at, if not where the error happened
+    Resume(Option<i32>),
+    ResumeNext,
+    Stop,
+    Cont,
     List,
     Load(String),
     Save(String),
@@ -35,23 +60,123 @@ pub enum ProgramSignal {
     End,
 }
 
+/// An item in the list following a print statement.
+#[derive(Clone)]
+pub enum PrintItem {
+    Expression(Expression),
+    /// Move to the given column, counting from 1.
+    Tab(Expression),
+    /// Print the given number of spaces.
+    Spc(Expression),
+    /// A `;`, which joins the items on either side with no gap.
+    Join,
+    /// A `,`, which moves to the start of the next print zone.
+    NextZone,
+}
+
+impl Display for PrintItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrintItem::Expression(exp) => write!(f, "{}", exp),
+            PrintItem::Tab(exp) => write!(f, "{}({})", TAB, exp),
+            PrintItem::Spc(exp) => write!(f, "{}({})", SPC, exp),
+            PrintItem::Join => write!(f, ";"),
+            PrintItem::NextZone => write!(f, ","),
+        }
+    }
+}
+
 /// A statement in a program.
+#[derive(Clone)]
 pub enum Statement {
     Empty,
     Rem(String),
-    Print(Vec<Expression>),
-    Let(char, Expression),
-    If(Condition, Box<Statement>),
-    Goto(i32),
-    Input(char),
-    Gosub(i32),
+    Print(Vec<PrintItem>),
+    /// Prints values laid out by a format string, e.g.
+    /// `print using "###.##"; x`. The flag is false when the list ends
+    /// with a separator, so the line carries on with the next print.
+    PrintUsing(Expression, Vec<Expression>, bool),
+    Let(Target, Expression),
+    Dim(Vec<(String, Vec<Expression>)>),
+    /// The statements following an `if` in the same line are only executed
+    /// when its condition is true. If there's an `else` later in the line,
+    /// the statements after it are executed instead when it's false.
+    If(Expression),
+    Else,
+    /// An `if` with nothing after its `then` starts a block if. The lines
+    /// up to the next `elseif`, `else` or `end if` are only executed when
+    /// its condition is true.
+    BlockIf(Expression),
+    ElseIf(Expression),
+    BlockElse,
+    EndIf,
+    Goto(Expression),
+    /// Reads values for one or more targets, after showing an optional
+    /// prompt.
+    Input(Option<String>, Vec<Target>),
+    Gosub(Expression),
+    /// Jumps to (or calls) the line chosen from the list by the value of
+    /// the expression, counting from 1. If there's no such line in the
+    /// list, execution carries on with the next statement.
+    OnGoto(Expression, Vec<Expression>),
+    OnGosub(Expression, Vec<Expression>),
+    /// Jumps to the given line when an error happens, instead of stopping
+    /// the program. Line 0 turns this off again.
+    OnError(i32),
+    /// Ends an error handler, going back to the statement where the error
+    /// happened, or to the given line.
+    Resume(Option<i32>),
+    /// Ends an error handler, going on from the statement after the one
+    /// where the error happened.
+    ResumeNext,
+    /// Defines a function of zero or more parameters, e.g.
+    /// `def fna(x) = x * x + 1`.
+    Def(String, Vec<String>, Expression),
+    /// Starts the definition of a procedure, which runs until the matching
+    /// `end sub` and is run with `call`. Its parameters and any variables
+    /// it assigns are local to each call.
+    Sub(String, Vec<String>),
+    EndSub,
+    /// Like a sub, but called from within an expression. The value it
+    /// returns is whatever was last assigned to its name.
+    Function(String, Vec<String>),
+    EndFunction,
+    Call(String, Vec<Expression>),
+    /// Values to be read by `read`. They're collected from the whole
+    /// program, in order, when it's run.
+    Data(Vec<Value>),
+    Read(Vec<Target>),
+    /// Start reading data again from the first item, or from the first
+    /// item at or after the given line.
+    Restore(Option<i32>),
+    /// Sets how large integers can get before arithmetic overflows.
+    Integer(IntegerMode),
+    /// Reseeds the random number generator with the given seed, so that
+    /// `rnd` gives the same numbers each time, or with an unpredictable
+    /// one if there's none.
+    Randomize(Option<Expression>),
     Return,
-    For(char, Expression, Expression, Option<Expression>),
-    Next,
+    For(String, Expression, Expression, Option<Expression>),
+    /// Ends the innermost `for` loop, or the loops of the named variables
+    /// in turn, e.g. `next j, i`.
+    Next(Vec<String>),
+    /// Repeats the lines up to the matching `wend` while its condition is
+    /// true.
+    While(Expression),
+    Wend,
+    /// Repeats the lines up to the matching `loop until` or `loop while`,
+    /// always running them at least once.
+    Do,
+    LoopUntil(Expression),
+    LoopWhile(Expression),
     List,
     Load(String),
     Save(String),
     Run,
+    /// Stops the program so that it can be continued with `cont`, after
+    /// looking at or changing variables in immediate mode.
+    Stop,
+    Cont,
     Clear,
     End,
 }
@@ -64,10 +189,7 @@ impl Statement {
     ///
     /// # Returns
     /// * Either an optional program flow or an error
-    pub fn execute(
-        &self,
-        variables: &mut HashMap<char, Number>,
-    ) -> Result<Option<ProgramSignal>, BasicError> {
+    pub fn execute(&self, variables: &mut Variables) -> Result<Option<ProgramSignal>, BasicError> {
         match self {
             Self::Empty => return Ok(None),
 
@@ -75,190 +197,265 @@ impl Statement {
                 return Ok(None);
             }
 
-            // Print the supplied expressions (if any).
-            Self::Print(args) => {
-                for arg in args.iter() {
-                    match arg {
-                        Expression::String(s) => {
-                            print!("{}", s);
-                        }
-                        Expression::Numeric(n) => {
-                            print!("{}", n);
-                        }
-                        Expression::Variable(c) => {
-                            print!("{}", eval_expression(Expression::Variable(*c), variables)?);
-                        }
-                        Expression::Operator(op, l_exp, r_exp) => {
-                            print!(
-                                "{}",
-                                eval_expression(
-                                    Expression::Operator(*op, l_exp.clone(), r_exp.clone()),
-                                    variables
-                                )?
-                            )
-                        }
-                        Expression::Function(name, args) => {
-                            print!("{}", eval_function(name, args, variables)?);
+            // Print the supplied items (if any). A separator at the end
+            // means the line carries on with the next print.
+            Self::Print(items) => {
+                for item in items.iter() {
+                    match item {
+                        PrintItem::Expression(exp) => {
+                            output::write(&eval_expression(exp, variables)?.to_string());
                         }
+                        PrintItem::Tab(exp) => output::tab(eval_count(exp, variables)?),
+                        PrintItem::Spc(exp) => output::spaces(eval_count(exp, variables)?),
+                        PrintItem::Join => (),
+                        PrintItem::NextZone => output::next_zone(),
                     }
                 }
 
-                println!();
+                match items.last() {
+                    Some(PrintItem::Join) | Some(PrintItem::NextZone) => output::flush(),
+                    _ => output::newline(),
+                }
             }
 
-            // Variable assignment command.
-            Self::Let(var, value) => match value {
-                Expression::String(_) => {
-                    return Err(BasicError::RuntimeError(String::from(
-                        "Can't assign strings to variables",
-                    )));
-                }
-                Expression::Numeric(n) => {
-                    variables.insert(*var, *n);
+            // Print the values as laid out by the format string.
+            Self::PrintUsing(format, exps, newline) => {
+                let Value::String(format) = eval_expression(format, variables)? else {
+                    return Err(BasicError::runtime(
+                        ErrorCode::TypeMismatch,
+                        "Print using needs a string format",
+                    ));
+                };
+
+                let mut values: Vec<Value> = Vec::new();
+                for exp in exps.iter() {
+                    values.push(eval_expression(exp, variables)?);
                 }
-                Expression::Variable(c) => {
-                    variables.insert(*var, eval_expression(Expression::Variable(*c), variables)?);
+
+                output::write(&format_using(&format, &values)?);
+
+                match newline {
+                    true => output::newline(),
+                    false => output::flush(),
                 }
-                Expression::Operator(op, l_exp, r_exp) => {
-                    variables.insert(
-                        *var,
-                        eval_expression(
-                            Expression::Operator(*op, l_exp.clone(), r_exp.clone()),
-                            variables,
-                        )?,
-                    );
+            }
+
+            // Variable assignment command.
+            Self::Let(target, value) => {
+                let val = eval_expression(value, variables)?;
+                target.assign(variables, val)?;
+            }
+
+            // Create one or more arrays.
+            Self::Dim(arrays) => {
+                for (name, bounds) in arrays.iter() {
+                    let bounds = eval_indices(bounds, variables)?;
+                    variables.dim(name, &bounds)?;
                 }
-                Expression::Function(name, args) => {
-                    variables.insert(*var, eval_function(name, args, variables)?);
+            }
+
+            // If statement takes a condition. When it evaluates to false,
+            // the consequent is skipped, moving on to the else part (if any).
+            Self::If(condition) => {
+                if !eval_condition(condition, variables)? {
+                    return Ok(Some(ProgramSignal::SkipToElse));
                 }
-            },
+            }
 
-            // If statement takes a condition and a consequent statement
-            // that's executed when the condition evaluates to true.
-            Self::If(condition, consequent) => match condition {
-                Condition::Boolean(l_exp, relop, r_exp) => {
-                    // Evaluate the left-hand and right-hand expressions.
-                    let l_val: Number = match l_exp {
-                        Expression::String(_) => {
-                            return Err(BasicError::RuntimeError(String::from(
-                                "Can't compare strings",
-                            )));
-                        }
-                        Expression::Numeric(n) => {
-                            eval_expression(Expression::Numeric(*n), variables)?
+            // Reaching an else means the consequent has just been executed,
+            // so the else part is skipped.
+            Self::Else => return Ok(Some(ProgramSignal::SkipLine)),
+
+            // The executor decides where to go next in a block if, since
+            // the branches span several lines.
+            Self::BlockIf(condition) => {
+                let taken = eval_condition(condition, variables)?;
+                return Ok(Some(ProgramSignal::BlockIf(taken)));
+            }
+
+            // Reaching the next branch of a block if means a branch has
+            // already been executed, so the rest are skipped.
+            Self::ElseIf(_) | Self::BlockElse => return Ok(Some(ProgramSignal::SkipToEndIf)),
+
+            Self::EndIf => return Ok(Some(ProgramSignal::EndIf)),
+
+            // Unconditional jump.
+            Self::Goto(exp) => {
+                return Ok(Some(ProgramSignal::Jump(eval_line_number(exp, variables)?)));
+            }
+
+            // Take input from the user.
+            Self::Input(prompt, targets) => {
+                let first_prompt = match prompt {
+                    Some(p) => format!("{}? ", p),
+                    None => String::from("? "),
+                };
+                let mut values: Vec<Value> = Vec::new();
+                let mut current_prompt = first_prompt.clone();
+
+                // Keep asking until there's a value for every target. Values
+                // can be typed all on one line or spread over several.
+                while values.len() < targets.len() {
+                    output::write(&current_prompt);
+                    output::flush();
+
+                    let mut buffer = String::new();
+
+                    match io::stdin().read_line(&mut buffer) {
+                        Ok(0) => {
+                            return Err(BasicError::runtime(
+                                ErrorCode::InputPastEnd,
+                                "No more input",
+                            ));
                         }
-                        Expression::Variable(v) => {
-                            eval_expression(Expression::Variable(*v), variables)?
+                        Ok(_) => output::reset_column(),
+                        Err(_) => {
+                            return Err(BasicError::runtime(ErrorCode::DeviceIo, "Input error"));
                         }
-                        Expression::Operator(op, l_exp, r_exp) => eval_expression(
-                            Expression::Operator(*op, l_exp.clone(), r_exp.clone()),
-                            variables,
-                        )?,
-                        Expression::Function(name, args) => eval_function(name, args, variables)?,
-                    };
+                    }
 
-                    let r_val: Number = match r_exp {
-                        Expression::String(_) => {
-                            return Err(BasicError::RuntimeError(String::from(
-                                "Can't compare strings",
-                            )));
-                        }
-                        Expression::Numeric(n) => {
-                            eval_expression(Expression::Numeric(*n), variables)?
+                    match parse_input(&buffer, &targets[values.len()..]) {
+                        Some(vals) => {
+                            values.extend(vals);
+                            current_prompt = String::from("?? ");
                         }
-                        Expression::Variable(v) => {
-                            eval_expression(Expression::Variable(*v), variables)?
+                        // Bad input means starting again from the first value.
+                        None => {
+                            output::write("Redo from start");
+                            output::newline();
+                            values.clear();
+                            current_prompt = first_prompt.clone();
                         }
-                        Expression::Operator(op, l_exp, r_exp) => eval_expression(
-                            Expression::Operator(*op, l_exp.clone(), r_exp.clone()),
-                            variables,
-                        )?,
-                        Expression::Function(name, args) => eval_function(name, args, variables)?,
-                    };
-
-                    // Once the two expressions are evaluated, test the condition.
-                    if self.eval_relop(l_val, *relop, r_val) {
-                        // If true, then execute the consequent.
-                        return consequent.execute(variables);
                     }
                 }
-            },
 
-            // Unconditional jump.
-            Self::Goto(n) => return Ok(Some(ProgramSignal::Jump(*n))),
+                for (target, val) in targets.iter().zip(values) {
+                    target.assign(variables, val)?;
+                }
+            }
 
-            // Take input from the user.
-            Self::Input(v) => {
-                // Print a prompt.
-                print!("? ");
-                io::stdout().flush().unwrap();
-
-                let mut buffer = String::new();
-                let stdin = io::stdin();
-                let res = stdin.read_line(&mut buffer);
-
-                match res {
-                    // Parse the input into a number
-                    Ok(_) => match buffer.trim().parse::<i32>() {
-                        Ok(i) => {
-                            variables.insert(*v, Number::Integer(i));
-                        }
-                        Err(_) => match buffer.trim().parse::<f64>() {
-                            Ok(f) => {
-                                variables.insert(*v, Number::Float(f));
-                            }
-                            Err(_) => {
-                                return Err(BasicError::RuntimeError(String::from("Parse error")));
-                            }
-                        },
-                    },
-                    Err(_) => {
-                        return Err(BasicError::RuntimeError(String::from("Input error")));
-                    }
+            // Define a user function.
+            Self::Def(name, params, body) => define_function(name, params, body, variables)?,
+
+            // Procedure definitions are skipped over when reached in the
+            // normal flow of the program; they only run when called.
+            Self::Sub(_, _) | Self::Function(_, _) => {
+                return Ok(Some(ProgramSignal::SkipProcedure));
+            }
+
+            Self::EndSub | Self::EndFunction => return Ok(Some(ProgramSignal::EndProcedure)),
+
+            // Call a procedure, ignoring the result if it's a function.
+            Self::Call(name, args) => {
+                call_procedure(name, args, variables)?;
+            }
+
+            // Data is collected before the program runs, so there's nothing
+            // to do here.
+            Self::Data(_) => (),
+
+            // Read the next data item into each target in turn.
+            // Numbers in data lines are kept exactly, like other literals,
+            // and fitted to the integer mode as they're read.
+            Self::Read(targets) => {
+                for target in targets.iter() {
+                    let val = match variables.read_data()? {
+                        Value::Number(n) => Value::Number(n.fitted()),
+                        val => val,
+                    };
+                    target.assign(variables, val)?;
                 }
             }
 
+            Self::Restore(line_num) => variables.restore_data(*line_num),
+
+            Self::Integer(mode) => set_integer_mode(*mode),
+
+            Self::Randomize(seed) => {
+                let seed = match seed {
+                    Some(exp) => Some(seed_value(
+                        &eval_expression(exp, variables)?.number_value()?,
+                    )),
+                    None => None,
+                };
+                variables.randomize(seed);
+            }
+
             // Goto subroutine
-            Self::Gosub(line_num) => return Ok(Some(ProgramSignal::Call(*line_num))),
+            Self::Gosub(exp) => {
+                return Ok(Some(ProgramSignal::Call(eval_line_number(exp, variables)?)));
+            }
+
+            // Computed jump or subroutine call.
+            Self::OnGoto(selector, targets) | Self::OnGosub(selector, targets) => {
+                let choice = eval_expression(selector, variables)?
+                    .number_value()?
+                    .float_value()
+                    .trunc() as i64;
+
+                if choice < 1 || choice as usize > targets.len() {
+                    return Ok(None);
+                }
+
+                let line_num = eval_line_number(&targets[choice as usize - 1], variables)?;
+
+                return match self {
+                    Self::OnGosub(_, _) => Ok(Some(ProgramSignal::Call(line_num))),
+                    _ => Ok(Some(ProgramSignal::Jump(line_num))),
+                };
+            }
 
             // Return from subroutine
             Self::Return => return Ok(Some(ProgramSignal::Return)),
 
+            Self::OnError(line_num) => match line_num {
+                0 => variables.set_error_handler(None),
+                n => variables.set_error_handler(Some(*n)),
+            },
+
+            Self::Resume(line_num) => return Ok(Some(ProgramSignal::Resume(*line_num))),
+
+            Self::ResumeNext => return Ok(Some(ProgramSignal::ResumeNext)),
+
             // Begin next iteration of a loop
             Self::For(var, start_val, end_val, maybe_step_val) => {
-                let step_val: Option<i32> = match maybe_step_val {
+                let final_start_val = eval_expression(start_val, variables)?.number_value()?;
+                let final_end_val = eval_expression(end_val, variables)?.number_value()?;
+                let step_val = match maybe_step_val {
                     None => None,
-                    Some(exp) => {
-                        let final_step_val = eval_expression(exp.clone(), variables)?;
-                        if !final_step_val.is_int() {
-                            return Err(BasicError::RuntimeError(String::from(
-                                "Values in for statement must be integers",
-                            )));
-                        } else {
-                            Some(final_step_val.int_value().expect("Type error"))
-                        }
-                    }
+                    Some(exp) => Some(eval_expression(exp, variables)?.number_value()?),
                 };
 
-                let final_start_val = eval_expression(start_val.clone(), variables)?;
-                let final_end_val = eval_expression(end_val.clone(), variables)?;
-
-                if !final_start_val.is_int() || !final_end_val.is_int() {
-                    return Err(BasicError::RuntimeError(String::from(
-                        "Values in for statement must be integers",
-                    )));
-                } else {
-                    return Ok(Some(ProgramSignal::StartLoop(
-                        *var,
-                        final_start_val.int_value().expect("Type error"),
-                        final_end_val.int_value().expect("Type error"),
-                        step_val,
-                    )));
-                }
+                return Ok(Some(ProgramSignal::StartLoop(
+                    var.clone(),
+                    final_start_val,
+                    final_end_val,
+                    step_val,
+                )));
             }
 
             // Evaluate whether to continue with another loop
-            Self::Next => return Ok(Some(ProgramSignal::EndLoop)),
+            Self::Next(vars) => return Ok(Some(ProgramSignal::EndLoop(vars.clone()))),
+
+            // The executor keeps track of where while and do loops start.
+            Self::While(condition) => {
+                let enter = eval_condition(condition, variables)?;
+                return Ok(Some(ProgramSignal::StartWhile(enter)));
+            }
+
+            Self::Wend => return Ok(Some(ProgramSignal::EndWhile)),
+
+            Self::Do => return Ok(Some(ProgramSignal::StartDo)),
+
+            Self::LoopUntil(condition) => {
+                let finished = eval_condition(condition, variables)?;
+                return Ok(Some(ProgramSignal::EndDo(!finished)));
+            }
+
+            Self::LoopWhile(condition) => {
+                let repeat = eval_condition(condition, variables)?;
+                return Ok(Some(ProgramSignal::EndDo(repeat)));
+            }
 
             // List the program
             Self::List => return Ok(Some(ProgramSignal::List)),
@@ -271,6 +468,8 @@ impl Statement {
 
             // Run the program
             Self::Run => return Ok(Some(ProgramSignal::Run)),
+            Self::Stop => return Ok(Some(ProgramSignal::Stop)),
+            Self::Cont => return Ok(Some(ProgramSignal::Cont)),
 
             // Clear all variables
             Self::Clear => return Ok(Some(ProgramSignal::ClearVars)),
@@ -278,18 +477,7 @@ impl Statement {
             Self::End => return Ok(Some(ProgramSignal::End)),
         }
 
-        return Ok(None);
-    }
-
-    fn eval_relop(&self, l_val: Number, relop: Relop, r_val: Number) -> bool {
-        match relop {
-            Relop::EQ => l_val == r_val,
-            Relop::NEQ => l_val != r_val,
-            Relop::LT => l_val < r_val,
-            Relop::LTE => l_val <= r_val,
-            Relop::GT => l_val > r_val,
-            Relop::GTE => l_val >= r_val,
-        }
+        Ok(None)
     }
 }
 
@@ -297,25 +485,112 @@ impl Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Statement::Rem(c) => write!(f, "{} {}", REM, c),
-            Statement::Print(args) => {
-                let mut output = String::from(format!("{} ", PRINT));
-                let mut first = true;
+            Statement::Print(items) => {
+                write!(f, "{}", PRINT)?;
 
-                for arg in args.iter() {
-                    if !first {
-                        output.push_str(", ");
+                for (idx, item) in items.iter().enumerate() {
+                    // Separators are followed by a space, unless at the end.
+                    match (idx, item) {
+                        (0, _) => write!(f, " {}", item)?,
+                        (_, PrintItem::Join | PrintItem::NextZone) => write!(f, "{}", item)?,
+                        (_, _) => match items[idx - 1] {
+                            PrintItem::Join | PrintItem::NextZone => write!(f, " {}", item)?,
+                            _ => write!(f, "{}", item)?,
+                        },
                     }
-                    output.push_str(&format!("{}", arg));
-                    first = false;
                 }
 
-                write!(f, "{}", output)
+                Ok(())
+            }
+            Statement::PrintUsing(format, exps, newline) => {
+                write!(f, "{} {} {}; {}", PRINT, USING, format, join(exps))?;
+
+                match newline {
+                    true => Ok(()),
+                    false => write!(f, ";"),
+                }
             }
             Statement::Let(var, exp) => write!(f, "{} {}={}", LET, var, exp),
-            Statement::If(con, stmnt) => write!(f, "{} {} {} {}", IF, con, THEN, stmnt),
+            Statement::Dim(arrays) => {
+                let arrays = arrays
+                    .iter()
+                    .map(|(name, bounds)| format!("{}({})", name, join(bounds)))
+                    .collect::<Vec<String>>();
+
+                write!(f, "{} {}", DIM, arrays.join(", "))
+            }
+            Statement::If(con) => write!(f, "{} {} {}", IF, con, THEN),
+            Statement::Else => write!(f, "{}", ELSE),
+            Statement::BlockIf(con) => write!(f, "{} {} {}", IF, con, THEN),
+            Statement::ElseIf(con) => write!(f, "{} {} {}", ELSEIF, con, THEN),
+            Statement::BlockElse => write!(f, "{}", ELSE),
+            Statement::EndIf => write!(f, "{} {}", END, IF),
             Statement::Goto(num) => write!(f, "{} {}", GOTO, num),
-            Statement::Input(var) => write!(f, "{} {}", INPUT, var),
+            Statement::Input(prompt, targets) => {
+                let targets = targets
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<String>>();
+
+                match prompt {
+                    Some(p) => write!(f, "{} \"{}\"; {}", INPUT, p, targets.join(", ")),
+                    None => write!(f, "{} {}", INPUT, targets.join(", ")),
+                }
+            }
             Statement::Gosub(num) => write!(f, "{} {}", GOSUB, num),
+            Statement::OnGoto(exp, targets) => {
+                write!(f, "{} {} {} {}", ON, exp, GOTO, join(targets))
+            }
+            Statement::OnGosub(exp, targets) => {
+                write!(f, "{} {} {} {}", ON, exp, GOSUB, join(targets))
+            }
+            Statement::OnError(line_num) => write!(f, "{} {} {} {}", ON, ERROR, GOTO, line_num),
+            Statement::Resume(line_num) => match line_num {
+                Some(n) => write!(f, "{} {}", RESUME, n),
+                None => write!(f, "{}", RESUME),
+            },
+            Statement::ResumeNext => write!(f, "{} {}", RESUME, NEXT),
+            Statement::Sub(name, params) => write!(f, "{} {}", SUB, fmt_params(name, params)),
+            Statement::EndSub => write!(f, "{} {}", END, SUB),
+            Statement::Function(name, params) => {
+                write!(f, "{} {}", FUNCTION, fmt_params(name, params))
+            }
+            Statement::EndFunction => write!(f, "{} {}", END, FUNCTION),
+            Statement::Data(values) => {
+                let values = values
+                    .iter()
+                    .map(|v| match v {
+                        Value::String(s) => format!("\"{}\"", s),
+                        Value::Number(n) => n.to_string(),
+                    })
+                    .collect::<Vec<String>>();
+
+                write!(f, "{} {}", DATA, values.join(", "))
+            }
+            Statement::Read(targets) => {
+                let targets = targets
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<String>>();
+
+                write!(f, "{} {}", READ, targets.join(", "))
+            }
+            Statement::Integer(mode) => write!(f, "{} {}", INTEGER, mode),
+            Statement::Randomize(seed) => match seed {
+                Some(exp) => write!(f, "{} {}", RANDOMIZE, exp),
+                None => write!(f, "{}", RANDOMIZE),
+            },
+            Statement::Restore(line_num) => match line_num {
+                Some(n) => write!(f, "{} {}", RESTORE, n),
+                None => write!(f, "{}", RESTORE),
+            },
+            Statement::Call(name, args) => match args.is_empty() {
+                true => write!(f, "{} {}", CALL, name),
+                false => write!(f, "{} {}({})", CALL, name, join(args)),
+            },
+            Statement::Def(name, params, body) => {
+                write!(f, "{} {}({})={}", DEF, name, params.join(", "), body)
+            }
             Statement::Return => write!(f, "{}", RETURN),
             Statement::For(var, start_val, end_val, step_val) => {
                 if let Some(v) = step_val {
@@ -328,9 +603,19 @@ impl Display for Statement {
                     write!(f, "{} {}={} {} {}", FOR, var, start_val, TO, end_val)
                 }
             }
-            Statement::Next => write!(f, "{}", NEXT),
+            Statement::Next(vars) => match vars.is_empty() {
+                true => write!(f, "{}", NEXT),
+                false => write!(f, "{} {}", NEXT, vars.join(", ")),
+            },
+            Statement::While(con) => write!(f, "{} {}", WHILE, con),
+            Statement::Wend => write!(f, "{}", WEND),
+            Statement::Do => write!(f, "{}", DO),
+            Statement::LoopUntil(con) => write!(f, "{} {} {}", LOOP, UNTIL, con),
+            Statement::LoopWhile(con) => write!(f, "{} {} {}", LOOP, WHILE, con),
             Statement::List => write!(f, "{}", LIST),
             Statement::Run => write!(f, "{}", RUN),
+            Statement::Stop => write!(f, "{}", STOP),
+            Statement::Cont => write!(f, "{}", CONT),
             Statement::Load(_) => Ok(()),
             Statement::Save(_) => Ok(()),
             Statement::End => write!(f, "{}", END),
@@ -340,19 +625,158 @@ impl Display for Statement {
     }
 }
 
+/// Parse a line typed in response to `input` into values for the given
+/// targets, in order. Values are separated by commas and strings may be
+/// quoted, but a string target at the end takes the rest of the line. There
+/// may be fewer values than targets, but not more.
+///
+/// # Returns
+/// * The values, or None if the input is invalid
+fn parse_input(line: &str, targets: &[Target]) -> Option<Vec<Value>> {
+    let mut values: Vec<Value> = Vec::new();
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+
+    for (idx, target) in targets.iter().enumerate() {
+        let is_last = idx == targets.len() - 1;
+        let trimmed = rest.trim_start();
+
+        // Split off the next field, which may be quoted.
+        let (field, remainder) = if let Some(quoted) = trimmed.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            let after = quoted[end + 1..].trim_start();
+
+            if !after.is_empty() && !after.starts_with(',') {
+                return None;
+            }
+            (quoted[..end].to_string(), after)
+        } else if is_last && is_string_var(target.name()) {
+            (trimmed.trim_end().to_string(), "")
+        } else {
+            match trimmed.find(',') {
+                Some(i) => (trimmed[..i].trim_end().to_string(), &trimmed[i..]),
+                None => (trimmed.trim_end().to_string(), ""),
+            }
+        };
+
+        let val = match is_string_var(target.name()) {
+            true => Value::String(field),
+            false => Value::Number(Number::parse(&field)?),
+        };
+        values.push(val);
+
+        // Move on to the next field, if there is one.
+        match remainder.strip_prefix(',') {
+            Some(r) => rest = r,
+            None => return Some(values),
+        }
+    }
+
+    // Anything left over means there were too many values.
+    None
+}
+
+/// Evaluate the argument to `tab` or `spc`. Negative counts are taken as 0,
+/// and counts over `output::MAX_COUNT` are an error.
+fn eval_count(exp: &Expression, variables: &mut Variables) -> Result<usize, BasicError> {
+    let count = eval_expression(exp, variables)?
+        .number_value()?
+        .float_value()
+        .trunc();
+
+    if count > output::MAX_COUNT as f64 {
+        return Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Tab or spc count over {}", output::MAX_COUNT),
+        ));
+    }
+
+    Ok(count.max(0.0) as usize)
+}
+
+/// Turn the value given to `randomize` into a seed. Any number will do, but
+/// an integer gives the same seed as `--seed` does.
+fn seed_value(n: &Number) -> u64 {
+    match n {
+        Number::Integer(i) => *i as u64,
+        _ => n.float_value().to_bits(),
+    }
+}
+
+/// Format the name and parameter list of a procedure definition.
+fn fmt_params(name: &str, params: &[String]) -> String {
+    match params.is_empty() {
+        true => name.to_string(),
+        false => format!("{}({})", name, params.join(", ")),
+    }
+}
+
+/// Evaluate the target of a goto or gosub, which must be a whole number.
+fn eval_line_number(exp: &Expression, variables: &mut Variables) -> Result<i32, BasicError> {
+    match eval_expression(exp, variables)?.number_value()? {
+        n if n.float_value().fract() == 0.0 && n.small_int_value().is_ok() => n.small_int_value(),
+        n => Err(BasicError::runtime(
+            ErrorCode::IllegalFunctionCall,
+            format!("Invalid line number {}", n),
+        )),
+    }
+}
+
+/// Format the statements of a line as source code. Statements are separated
+/// by colons, except around `then` and `else` where they follow directly.
+pub fn format_statements(statements: &[Statement]) -> String {
+    let mut output = String::new();
+
+    for (idx, stmt) in statements.iter().enumerate() {
+        if idx > 0 {
+            match (&statements[idx - 1], stmt) {
+                (Statement::If(_), _) | (Statement::Else, _) | (_, Statement::Else) => {
+                    output.push(' ')
+                }
+                _ => output.push_str(" : "),
+            }
+        }
+
+        output.push_str(&stmt.to_string());
+    }
+
+    output
+}
+
+/// Find the `else` that belongs to the `if` at the given index in a line,
+/// returning its index. Any ifs nested in the consequent claim the first
+/// elses that follow them.
+pub fn find_else(statements: &[Statement], if_idx: usize) -> Option<usize> {
+    let mut depth = 0;
+
+    for (idx, stmt) in statements.iter().enumerate().skip(if_idx + 1) {
+        match stmt {
+            Statement::If(_) => depth += 1,
+            Statement::Else if depth == 0 => return Some(idx),
+            Statement::Else => depth -= 1,
+            _ => (),
+        }
+    }
+
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::expression::ArithOp;
+    use std::collections::HashMap;
+
+    use crate::expression::{ArithOp, Relop};
 
     #[test]
     fn prints_string() {
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let mut variables = Variables::new();
         let mut lines: HashMap<i32, Statement> = HashMap::new();
 
         lines.insert(
             10,
-            Statement::Print(vec![Expression::String(String::from("Hello, world!"))]),
+            Statement::Print(vec![PrintItem::Expression(Expression::String(
+                String::from("Hello, world!"),
+            ))]),
         );
 
         match lines.get(&10).expect("Error").execute(&mut variables) {
@@ -363,12 +787,15 @@ mod tests {
 
     #[test]
     fn assigns_variable() {
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let mut variables = Variables::new();
         let mut lines: HashMap<i32, Statement> = HashMap::new();
 
         lines.insert(
             10,
-            Statement::Let('A', Expression::Numeric(Number::Integer(42))),
+            Statement::Let(
+                Target::Variable(String::from("A")),
+                Expression::Numeric(Number::Integer(42)),
+            ),
         );
 
         match lines.get(&10).expect("Error").execute(&mut variables) {
@@ -376,42 +803,54 @@ mod tests {
             Err(e) => panic!("{}", e),
         }
 
-        assert_eq!(*variables.get(&'A').unwrap(), Number::Integer(42));
+        assert_eq!(
+            *variables.get("A").unwrap(),
+            Value::Number(Number::Integer(42))
+        );
     }
 
     #[test]
     fn evaluates_condition() {
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let mut variables = Variables::new();
         let mut lines: HashMap<i32, Statement> = HashMap::new();
 
-        variables.insert('A', Number::Integer(42));
+        variables
+            .set("A", Value::Number(Number::Integer(42)))
+            .unwrap();
 
         lines.insert(
             20,
-            Statement::If(
-                Condition::Boolean(
-                    Expression::Variable('A'),
-                    Relop::EQ,
-                    Expression::Numeric(Number::Integer(42)),
-                ),
-                Box::new(Statement::Let(
-                    'A',
-                    Expression::Numeric(Number::Integer(69)),
-                )),
-            ),
+            Statement::If(Expression::Relation(
+                Relop::EQ,
+                Box::new(Expression::Variable(String::from("A"))),
+                Box::new(Expression::Numeric(Number::Integer(42))),
+            )),
+        );
+        lines.insert(
+            30,
+            Statement::If(Expression::Relation(
+                Relop::NEQ,
+                Box::new(Expression::Variable(String::from("A"))),
+                Box::new(Expression::Numeric(Number::Integer(42))),
+            )),
         );
 
+        // A true condition carries on to the consequent...
         match lines.get(&20).expect("Error").execute(&mut variables) {
             Ok(maybe_flow) => assert!(maybe_flow.is_none()),
             Err(e) => panic!("{}", e),
         }
 
-        assert_eq!(*variables.get(&'A').unwrap(), Number::Integer(69));
+        // ...and a false one skips the rest of the line.
+        match lines.get(&30).expect("Error").execute(&mut variables) {
+            Ok(maybe_flow) => assert_eq!(maybe_flow, Some(ProgramSignal::SkipToElse)),
+            Err(e) => panic!("{}", e),
+        }
     }
 
     #[test]
     fn evaluates_complex_expressions() {
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let mut variables = Variables::new();
         let mut lines: HashMap<i32, Statement> = HashMap::new();
 
         let exp = Expression::Operator(
@@ -424,31 +863,45 @@ mod tests {
             ))),
         );
 
-        lines.insert(10, Statement::Let('N', exp));
+        lines.insert(10, Statement::Let(Target::Variable(String::from("N")), exp));
 
         match lines.get(&10).expect("Error").execute(&mut variables) {
             Ok(maybe_flow) => assert!(maybe_flow.is_none()),
             Err(e) => panic!("{}", e),
         }
 
-        assert_eq!(*variables.get(&'N').unwrap(), Number::Integer(14));
+        assert_eq!(
+            *variables.get("N").unwrap(),
+            Value::Number(Number::Integer(14))
+        );
     }
 
     #[test]
     fn branches_unconditionally() {
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let mut variables = Variables::new();
         let mut lines: HashMap<i32, Statement> = HashMap::new();
 
-        variables.insert('X', Number::Integer(1));
+        variables
+            .set("X", Value::Number(Number::Integer(1)))
+            .unwrap();
 
-        lines.insert(10, Statement::Goto(30));
+        lines.insert(
+            10,
+            Statement::Goto(Expression::Numeric(Number::Integer(30))),
+        );
         lines.insert(
             20,
-            Statement::Let('X', Expression::Numeric(Number::Integer(2))),
+            Statement::Let(
+                Target::Variable(String::from("X")),
+                Expression::Numeric(Number::Integer(2)),
+            ),
         );
         lines.insert(
             30,
-            Statement::Let('X', Expression::Numeric(Number::Integer(3))),
+            Statement::Let(
+                Target::Variable(String::from("X")),
+                Expression::Numeric(Number::Integer(3)),
+            ),
         );
 
         match lines.get(&10).expect("Error").execute(&mut variables) {
@@ -462,15 +915,17 @@ mod tests {
 
     #[test]
     fn loops() {
-        let mut variables: HashMap<char, Number> = HashMap::new();
+        let mut variables = Variables::new();
         let mut lines: HashMap<i32, Statement> = HashMap::new();
 
-        variables.insert('x', Number::Integer(0));
+        variables
+            .set("x", Value::Number(Number::Integer(0)))
+            .unwrap();
 
         lines.insert(
             10,
             Statement::For(
-                'i',
+                String::from("i"),
                 Expression::Numeric(Number::Integer(1)),
                 Expression::Numeric(Number::Integer(10)),
                 None,
@@ -479,15 +934,15 @@ mod tests {
         lines.insert(
             20,
             Statement::Let(
-                'x',
+                Target::Variable(String::from("x")),
                 Expression::Operator(
                     ArithOp::Add,
-                    Some(Box::new(Expression::Variable('x'))),
+                    Some(Box::new(Expression::Variable(String::from("x")))),
                     Some(Box::new(Expression::Numeric(Number::Integer(1)))),
                 ),
             ),
         );
-        lines.insert(30, Statement::Next);
+        lines.insert(30, Statement::Next(vec![]));
 
         let _ = lines.get(&10).expect("Error").execute(&mut variables);
         let _ = lines.get(&20).expect("Error").execute(&mut variables);
@@ -495,8 +950,8 @@ mod tests {
 
         match signal {
             Ok(maybe_signal) => match maybe_signal {
-                Some(ProgramSignal::EndLoop) => {
-                    if let Number::Integer(i) = variables.get(&'x').expect("Error") {
+                Some(ProgramSignal::EndLoop(_)) => {
+                    if let Value::Number(Number::Integer(i)) = variables.get("x").expect("Error") {
                         assert_eq!(*i, 1);
                     }
                 }
@@ -505,4 +960,128 @@ mod tests {
             Err(e) => panic!("{}", e),
         }
     }
+
+    #[test]
+    fn assigns_concatenated_strings() {
+        let mut variables = Variables::new();
+
+        variables
+            .set("a$", Value::String(String::from("Hello")))
+            .unwrap();
+
+        let stmt = Statement::Let(
+            Target::Variable(String::from("b$")),
+            Expression::Operator(
+                ArithOp::Add,
+                Some(Box::new(Expression::Variable(String::from("a$")))),
+                Some(Box::new(Expression::String(String::from(", world")))),
+            ),
+        );
+
+        if let Err(e) = stmt.execute(&mut variables) {
+            panic!("{}", e);
+        }
+
+        assert_eq!(
+            *variables.get("b$").unwrap(),
+            Value::String(String::from("Hello, world"))
+        );
+    }
+
+    #[test]
+    fn rejects_huge_print_counts() {
+        let mut variables = Variables::new();
+        let huge = || Expression::Numeric(Number::Float(1e18));
+
+        for item in [PrintItem::Spc(huge()), PrintItem::Tab(huge())] {
+            match Statement::Print(vec![item]).execute(&mut variables) {
+                Err(e) => assert_eq!(e.code, ErrorCode::IllegalFunctionCall),
+                Ok(_) => panic!("Expected an error"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_assignment() {
+        let mut variables = Variables::new();
+
+        let stmt = Statement::Let(
+            Target::Variable(String::from("a")),
+            Expression::String(String::from("Hello")),
+        );
+        assert!(stmt.execute(&mut variables).is_err());
+
+        let stmt = Statement::Let(
+            Target::Variable(String::from("a$")),
+            Expression::Numeric(Number::Integer(1)),
+        );
+        assert!(stmt.execute(&mut variables).is_err());
+    }
+
+    #[test]
+    fn compares_strings() {
+        let mut variables = Variables::new();
+
+        variables
+            .set("n$", Value::String(String::from("abc")))
+            .unwrap();
+
+        let stmt = Statement::If(Expression::Relation(
+            Relop::LT,
+            Box::new(Expression::Variable(String::from("n$"))),
+            Box::new(Expression::String(String::from("abd"))),
+        ));
+
+        match stmt.execute(&mut variables) {
+            Ok(maybe_flow) => assert!(maybe_flow.is_none()),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn finds_matching_else() {
+        let cond = || Expression::Numeric(Number::Integer(1));
+
+        // if 1 then if 1 then end else end else end
+        let stmts = vec![
+            Statement::If(cond()),
+            Statement::If(cond()),
+            Statement::End,
+            Statement::Else,
+            Statement::End,
+            Statement::Else,
+            Statement::End,
+        ];
+
+        assert_eq!(find_else(&stmts, 0), Some(5));
+        assert_eq!(find_else(&stmts, 1), Some(3));
+        assert_eq!(find_else(&stmts[..5], 0), None);
+    }
+
+    #[test]
+    fn parses_input() {
+        let targets = vec![
+            Target::Variable(String::from("a")),
+            Target::Variable(String::from("b$")),
+            Target::Variable(String::from("c$")),
+        ];
+        let num = |i| Value::Number(Number::Integer(i));
+        let text = |s: &str| Value::String(String::from(s));
+
+        assert_eq!(
+            parse_input("1, \"x, y\" , the rest, too\n", &targets),
+            Some(vec![num(1), text("x, y"), text("the rest, too")])
+        );
+        assert_eq!(
+            parse_input("2.5\n", &targets[..1]),
+            Some(vec![Value::Number(Number::Float(2.5))])
+        );
+        assert_eq!(
+            parse_input("3, hi\n", &targets),
+            Some(vec![num(3), text("hi")])
+        );
+        assert_eq!(parse_input("x\n", &targets), None);
+        assert_eq!(parse_input("1, 2\n", &targets[..1]), None);
+        assert_eq!(parse_input("\"open\n", &targets[1..]), None);
+    }
 }
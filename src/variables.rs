@@ -0,0 +1,478 @@
+use std::{collections::HashMap, rc::Rc};
+
+use rand::{SeedableRng, rngs::StdRng};
+
+use crate::{
+    errors::{BasicError, ErrorCode},
+    expression::{Value, is_string_var, type_mismatch},
+    function::UserFunction,
+    number::Number,
+    program::{Position, Procedure},
+};
+
+/// The largest number of elements an array may hold.
+const MAX_ARRAY_SIZE: usize = 1_000_000;
+
+/// How deeply sub and function calls can be nested, unless configured
+/// otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+
+/// The deepest that sub and function calls can be allowed to nest, which
+/// the interpreter's stack is sized for.
+pub const MAX_DEPTH_LIMIT: usize = 1000;
+
+/// An array of values of any number of dimensions, stored flat in
+/// row-major order.
+struct Array {
+    /// The upper bound of each dimension. Indices run from 0 up to and
+    /// including the bound.
+    bounds: Vec<usize>,
+
+    values: Vec<Value>,
+}
+
+impl Array {
+    /// Find the position in the flat list of values of the element with
+    /// the given indices.
+    fn offset(&self, name: &str, indices: &[i32]) -> Result<usize, BasicError> {
+        if indices.len() != self.bounds.len() {
+            return Err(BasicError::runtime(
+                ErrorCode::SubscriptOutOfRange,
+                format!("Wrong number of subscripts for array {}", name),
+            ));
+        }
+
+        let mut offset = 0;
+
+        for (idx, bound) in indices.iter().zip(self.bounds.iter()) {
+            if *idx < 0 || *idx as usize > *bound {
+                return Err(BasicError::runtime(
+                    ErrorCode::SubscriptOutOfRange,
+                    format!(
+                        "Subscript out of range in {}({})",
+                        name,
+                        indices
+                            .iter()
+                            .map(|i| i.to_string())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                ));
+            }
+
+            offset = offset * (bound + 1) + *idx as usize;
+        }
+
+        Ok(offset)
+    }
+}
+
+/// The variable table, holding both simple variables and arrays. A simple
+/// variable and an array can share the same name without conflict. User
+/// functions defined with `def` and procedures defined with `sub` or
+/// `function` are kept here too.
+pub struct Variables {
+    scalars: HashMap<String, Value>,
+    arrays: HashMap<String, Array>,
+    functions: HashMap<String, UserFunction>,
+    procedures: HashMap<String, Rc<Procedure>>,
+
+    /// The local variables of each procedure call in progress, innermost
+    /// last.
+    frames: Vec<HashMap<String, Value>>,
+    max_depth: usize,
+
+    /// The program's data items, with the number of the line each is on,
+    /// and the index of the next one to be read.
+    data: Vec<(i32, Value)>,
+    data_pointer: usize,
+
+    /// The random number generator used by `rnd`, and the seed it's reset
+    /// to each time a program is run, if one was given.
+    rng: StdRng,
+    seed: Option<u64>,
+
+    /// The line to jump to when an error happens, the code and line number
+    /// of the last error, and where it happened while its handler is
+    /// running.
+    error_handler: Option<i32>,
+    last_error: (i32, i32),
+    resume_at: Option<Position>,
+}
+
+impl Default for Variables {
+    fn default() -> Self {
+        Variables {
+            scalars: HashMap::new(),
+            arrays: HashMap::new(),
+            functions: HashMap::new(),
+            procedures: HashMap::new(),
+            frames: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            data: Vec::new(),
+            data_pointer: 0,
+            rng: StdRng::from_os_rng(),
+            seed: None,
+            error_handler: None,
+            last_error: (0, 0),
+            resume_at: None,
+        }
+    }
+}
+
+impl Variables {
+    pub fn new() -> Variables {
+        Variables::default()
+    }
+
+    /// Get the value of a simple variable, if it has been set. Inside a
+    /// procedure call, its local variables hide any global ones with the
+    /// same name.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self.frames.last().and_then(|frame| frame.get(name)) {
+            Some(val) => Some(val),
+            None => self.scalars.get(name),
+        }
+    }
+
+    /// Assign a value to a simple variable, checking that the value's type
+    /// matches the variable's (string variables end in `$`). Inside a
+    /// procedure call, the variable is local to the call.
+    pub fn set(&mut self, name: &str, val: Value) -> Result<(), BasicError> {
+        check_type(name, &val)?;
+
+        match self.frames.last_mut() {
+            Some(frame) => frame.insert(name.to_string(), val),
+            None => self.scalars.insert(name.to_string(), val),
+        };
+
+        Ok(())
+    }
+
+    /// Start a procedure call with the given local variables.
+    pub fn push_frame(&mut self, frame: HashMap<String, Value>) -> Result<(), BasicError> {
+        if self.frames.len() >= self.max_depth {
+            return Err(BasicError::runtime(
+                ErrorCode::OutOfMemory,
+                format!("Procedure calls nested more than {} deep", self.max_depth),
+            ));
+        }
+
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
+    /// Finish the innermost procedure call, returning its local variables.
+    pub fn pop_frame(&mut self) -> Option<HashMap<String, Value>> {
+        self.frames.pop()
+    }
+
+    /// Set how deeply procedure calls can be nested, up to the most the
+    /// interpreter can manage.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth.min(MAX_DEPTH_LIMIT);
+    }
+
+    /// Seed the random number generator, and reseed it with the same seed
+    /// whenever a program is run, so that runs can be repeated exactly.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.randomize(Some(seed));
+    }
+
+    /// Reseed the random number generator with the given seed, or an
+    /// unpredictable one if there's none.
+    pub fn randomize(&mut self, seed: Option<u64>) {
+        self.rng = match seed {
+            Some(s) => StdRng::seed_from_u64(s),
+            None => StdRng::from_os_rng(),
+        };
+    }
+
+    /// Get the random number generator used by `rnd`.
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// Set the line to jump to when an error happens, or stop trapping
+    /// errors if there's none.
+    pub fn set_error_handler(&mut self, line_num: Option<i32>) {
+        self.error_handler = line_num;
+    }
+
+    /// Get the line to jump to when an error happens, unless the handler
+    /// is already dealing with one.
+    pub fn error_handler(&self) -> Option<i32> {
+        match self.resume_at {
+            Some(_) => None,
+            None => self.error_handler,
+        }
+    }
+
+    /// Record an error that's about to be handled: its code, the number
+    /// of the line it happened in, and where to resume from.
+    pub fn trap_error(&mut self, code: i32, line_num: i32, pos: Position) {
+        self.last_error = (code, line_num);
+        self.resume_at = Some(pos);
+    }
+
+    /// Get the code and line number of the last error handled.
+    pub fn last_error(&self) -> (i32, i32) {
+        self.last_error
+    }
+
+    /// Finish handling an error, returning where it happened.
+    pub fn resume(&mut self) -> Option<Position> {
+        self.resume_at.take()
+    }
+
+    /// Create an array with the given upper bound for each dimension. All
+    /// elements start as 0 (or an empty string for string arrays).
+    pub fn dim(&mut self, name: &str, bounds: &[i32]) -> Result<(), BasicError> {
+        if self.arrays.contains_key(name) {
+            return Err(BasicError::runtime(
+                ErrorCode::DuplicateDefinition,
+                format!("Array {} already dimensioned", name),
+            ));
+        }
+
+        let mut size: usize = 1;
+
+        for bound in bounds.iter() {
+            if *bound < 0 {
+                return Err(BasicError::runtime(
+                    ErrorCode::IllegalFunctionCall,
+                    format!("Negative dimension for array {}", name),
+                ));
+            }
+
+            size = size.saturating_mul(*bound as usize + 1);
+        }
+
+        if size > MAX_ARRAY_SIZE {
+            return Err(BasicError::runtime(
+                ErrorCode::OutOfMemory,
+                format!("Array {} is too large", name),
+            ));
+        }
+
+        let initial = match is_string_var(name) {
+            true => Value::String(String::new()),
+            false => Value::Number(Number::Integer(0)),
+        };
+
+        self.arrays.insert(
+            name.to_string(),
+            Array {
+                bounds: bounds.iter().map(|b| *b as usize).collect(),
+                values: vec![initial; size],
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Get the value of an array element.
+    pub fn get_element(&self, name: &str, indices: &[i32]) -> Result<Value, BasicError> {
+        let array = self.array(name)?;
+        let offset = array.offset(name, indices)?;
+
+        Ok(array.values[offset].clone())
+    }
+
+    /// Assign a value to an array element.
+    pub fn set_element(
+        &mut self,
+        name: &str,
+        indices: &[i32],
+        val: Value,
+    ) -> Result<(), BasicError> {
+        check_type(name, &val)?;
+
+        let array = match self.arrays.get_mut(name) {
+            Some(a) => a,
+            None => return Err(not_dimensioned(name)),
+        };
+        let offset = array.offset(name, indices)?;
+        array.values[offset] = val;
+
+        Ok(())
+    }
+
+    /// Get the definition of a user function, if it has been defined.
+    pub fn get_function(&self, name: &str) -> Option<&UserFunction> {
+        self.functions.get(name)
+    }
+
+    /// Store the definition of a user function, replacing any existing one.
+    pub fn define_function(&mut self, name: &str, function: UserFunction) {
+        self.functions.insert(name.to_string(), function);
+    }
+
+    /// Get a procedure defined with `sub` or `function`.
+    pub fn get_procedure(&self, name: &str) -> Option<Rc<Procedure>> {
+        self.procedures.get(name).cloned()
+    }
+
+    /// Store a procedure, replacing any existing one.
+    pub fn define_procedure(&mut self, name: &str, procedure: Procedure) {
+        self.procedures.insert(name.to_string(), Rc::new(procedure));
+    }
+
+    /// Replace the data items to be read, starting from the first.
+    pub fn set_data(&mut self, data: Vec<(i32, Value)>) {
+        self.data = data;
+        self.data_pointer = 0;
+    }
+
+    /// Get the next data item.
+    pub fn read_data(&mut self) -> Result<Value, BasicError> {
+        match self.data.get(self.data_pointer) {
+            Some((_, val)) => {
+                self.data_pointer += 1;
+                Ok(val.clone())
+            }
+            None => Err(BasicError::runtime(ErrorCode::OutOfData, "Out of data")),
+        }
+    }
+
+    /// Go back to reading from the first data item, or from the first one
+    /// at or after the given line.
+    pub fn restore_data(&mut self, line_num: Option<i32>) {
+        self.data_pointer = match line_num {
+            Some(n) => self
+                .data
+                .iter()
+                .position(|(l, _)| *l >= n)
+                .unwrap_or(self.data.len()),
+            None => 0,
+        };
+    }
+
+    /// Remove all global variables and arrays.
+    pub fn clear(&mut self) {
+        self.scalars.clear();
+        self.arrays.clear();
+    }
+
+    /// Remove everything, including user functions and procedures, ready
+    /// for a program to be run.
+    pub fn reset(&mut self) {
+        self.clear();
+        self.functions.clear();
+        self.procedures.clear();
+        self.frames.clear();
+        self.set_data(Vec::new());
+        self.error_handler = None;
+        self.last_error = (0, 0);
+        self.resume_at = None;
+
+        if let Some(seed) = self.seed {
+            self.randomize(Some(seed));
+        }
+    }
+
+    fn array(&self, name: &str) -> Result<&Array, BasicError> {
+        match self.arrays.get(name) {
+            Some(a) => Ok(a),
+            None => Err(not_dimensioned(name)),
+        }
+    }
+}
+
+fn check_type(name: &str, val: &Value) -> Result<(), BasicError> {
+    match (val, is_string_var(name)) {
+        (Value::String(_), true) | (Value::Number(_), false) => Ok(()),
+        _ => Err(type_mismatch()),
+    }
+}
+
+fn not_dimensioned(name: &str) -> BasicError {
+    BasicError::runtime(
+        ErrorCode::SubscriptOutOfRange,
+        format!("Array {} not dimensioned", name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_array_elements() {
+        let mut variables = Variables::new();
+
+        variables
+            .dim("a", &[2, 3])
+            .expect("Error dimensioning array");
+        variables
+            .set_element("a", &[1, 2], Value::Number(Number::Integer(7)))
+            .expect("Error setting element");
+
+        assert_eq!(
+            variables.get_element("a", &[1, 2]).unwrap(),
+            Value::Number(Number::Integer(7))
+        );
+        assert_eq!(
+            variables.get_element("a", &[2, 3]).unwrap(),
+            Value::Number(Number::Integer(0))
+        );
+    }
+
+    #[test]
+    fn checks_bounds() {
+        let mut variables = Variables::new();
+
+        variables.dim("a", &[5]).expect("Error dimensioning array");
+
+        assert!(variables.get_element("a", &[6]).is_err());
+        assert!(variables.get_element("a", &[-1]).is_err());
+        assert!(variables.get_element("a", &[1, 1]).is_err());
+        assert!(variables.get_element("b", &[1]).is_err());
+    }
+
+    #[test]
+    fn keeps_locals_in_frames() {
+        let mut variables = Variables::new();
+        let one = Value::Number(Number::Integer(1));
+        let two = Value::Number(Number::Integer(2));
+
+        variables.set("a", one.clone()).unwrap();
+        variables.set("b", one.clone()).unwrap();
+        variables.push_frame(HashMap::new()).unwrap();
+        variables.set("a", two.clone()).unwrap();
+
+        assert_eq!(variables.get("a"), Some(&two));
+        assert_eq!(variables.get("b"), Some(&one));
+
+        variables.pop_frame();
+
+        assert_eq!(variables.get("a"), Some(&one));
+    }
+
+    #[test]
+    fn limits_call_depth() {
+        let mut variables = Variables::new();
+        variables.set_max_depth(2);
+
+        assert!(variables.push_frame(HashMap::new()).is_ok());
+        assert!(variables.push_frame(HashMap::new()).is_ok());
+        assert!(variables.push_frame(HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn reads_data() {
+        let mut variables = Variables::new();
+        let num = |i| Value::Number(Number::Integer(i));
+
+        variables.set_data(vec![(10, num(1)), (10, num(2)), (30, num(3))]);
+
+        assert_eq!(variables.read_data().unwrap(), num(1));
+        variables.restore_data(Some(20));
+        assert_eq!(variables.read_data().unwrap(), num(3));
+        assert!(variables.read_data().is_err());
+        variables.restore_data(None);
+        assert_eq!(variables.read_data().unwrap(), num(1));
+    }
+}